@@ -0,0 +1,106 @@
+use bo_cc::{interesting_forms_in, patterns_in};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use encoding_rs::{Encoding, SHIFT_JIS, UTF_8, WINDOWS_1252};
+
+/// An HTML page with `nr_forms` forms, every other one with patterns, and
+/// `nr_paragraphs` paragraphs of `filler` around them.
+fn page(nr_forms: usize, nr_paragraphs: usize, filler: &str) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head><title>Sample</title></head>\n<body>\n",
+    );
+    for i in 0..nr_paragraphs {
+        html.push_str(&format!("<p>{i}: {filler}</p>\n"));
+        if nr_paragraphs < nr_forms || i % (nr_paragraphs / nr_forms) != 0 {
+            continue;
+        }
+        let nr_form = i / (nr_paragraphs / nr_forms);
+        if nr_form >= nr_forms {
+            continue;
+        }
+        html.push_str(&format!(
+            "<form action=\"/submit/{nr_form}\" method=\"post\">\n"
+        ));
+        if nr_form % 2 == 0 {
+            html.push_str(
+                "<input name=\"zip\" pattern=\"[0-9]{5}\" required placeholder=\"12345\">\n\
+                 <input name=\"phone\" data-val-regex-pattern=\"\\+?[0-9 ]+\">\n",
+            );
+        } else {
+            html.push_str("<input name=\"q\" type=\"search\">\n");
+        }
+        html.push_str("<select name=\"country\"><option value=\"se\">Sweden</option></select>\n");
+        html.push_str("<button type=\"submit\">Send</button>\n</form>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Wrap `body` in an HTTP response, like the content of a WARC response
+/// record. Without a `charset`, the encoding has to be guessed.
+fn response(html: &str, encoding: &'static Encoding, declare_charset: bool) -> Vec<u8> {
+    let (body, _, _) = encoding.encode(html);
+    let content_type = if declare_charset {
+        format!("text/html; charset={}", encoding.name())
+    } else {
+        "text/html".to_owned()
+    };
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(&body);
+    response
+}
+
+fn samples() -> Vec<(&'static str, Vec<u8>)> {
+    let latin = "Lorem ipsum dolor sit amet, consectetur adipiscing elit.";
+    let accented = "Hämta dina försändelser från ombudet på torget, tack så mycket.";
+    let japanese = "郵便番号を入力してください。電話番号は半角数字で入力してください。";
+    vec![
+        ("small-utf8", response(&page(2, 10, latin), UTF_8, true)),
+        ("large-utf8", response(&page(20, 5_000, latin), UTF_8, true)),
+        (
+            "medium-windows-1252-guessed",
+            response(&page(10, 500, accented), WINDOWS_1252, false),
+        ),
+        (
+            "medium-shift-jis-guessed",
+            response(&page(10, 500, japanese), SHIFT_JIS, false),
+        ),
+    ]
+}
+
+fn bench_extraction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interesting_forms_in");
+    for (name, sample) in samples() {
+        group.throughput(Throughput::Bytes(sample.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &sample, |b, sample| {
+            b.iter(|| interesting_forms_in(black_box(sample)).ok())
+        });
+    }
+    group.finish();
+}
+
+fn bench_patterns(c: &mut Criterion) {
+    let forms: Vec<String> = samples()
+        .into_iter()
+        .flat_map(|(_, sample)| interesting_forms_in(&sample).unwrap_or_default())
+        .collect();
+    let nr_bytes: usize = forms.iter().map(|form| form.len()).sum();
+
+    let mut group = c.benchmark_group("patterns_in");
+    group.throughput(Throughput::Bytes(nr_bytes as u64));
+    group.bench_function("sample forms", |b| {
+        b.iter(|| {
+            forms
+                .iter()
+                .map(|form| patterns_in(black_box(form)).map_or(0, |p| p.len()))
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_extraction, bench_patterns);
+criterion_main!(benches);
@@ -1,120 +1,594 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::error::Error;
-use std::io::{self, BufRead};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use bo_cc::{elements_with, patterns_in, processed_warcs, to_storage_fn, ArchiveSummary};
+use bo_cc::{
+    all_forms_with_pattern_attrs, all_url_summaries, elements_matching_query, elements_with,
+    fnv_hash, form_action, init_logger, patterned_inputs_with, patterns_in_with, processed_in,
+    public_suffix, sample_key, select_options, summarize, ElementMatch, FormsDir, Stats,
+};
+use lazy_static::lazy_static;
+use log::warn;
 use rayon::prelude::*;
+use regex::RegexSet;
+use serde::Serialize;
+
+/// Longer URLs are cut short in file names, to stay within file system
+/// limits.
+const MAX_URL_IN_FILE_NAME: usize = 200;
+
+/// How often results written to `--output` are flushed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where results go: stdout, or a file given with `--output`. Files are
+/// appended to and flushed every `FLUSH_INTERVAL`, so an interrupted run
+/// keeps (nearly) everything it found so far.
+struct Output {
+    writer: Box<dyn Write + Send>,
+    last_flush: Instant,
+}
+
+impl Output {
+    fn append_to(file_name: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_name)?;
+        Ok(Output {
+            writer: Box::new(BufWriter::new(file)),
+            last_flush: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, args: fmt::Arguments) {
+        self.writer
+            .write_fmt(args)
+            .expect("Unable to write output!");
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.writer.flush().expect("Unable to flush output!");
+        self.last_flush = Instant::now();
+    }
+}
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Output> = Mutex::new(Output {
+        writer: Box::new(io::stdout()),
+        last_flush: Instant::now(),
+    });
+}
+
+/// Like `print!`, but to `OUTPUT`. Each call is written in one go, so output
+/// from different threads doesn't interleave within it.
+macro_rules! out {
+    ($($arg:tt)*) => {
+        OUTPUT.lock().unwrap().write(format_args!($($arg)*))
+    };
+}
+
+/// Like `println!`, but to `OUTPUT`.
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        out!("{}\n", format_args!($($arg)*))
+    };
+}
 
 enum Cmd {
     Summary,
     Patterns,
     Forms,
     FindPattern,
+    Merge,
+    Required,
+    UniquePatterns,
+    FindInput,
+    Selects,
+    Diff,
+    TldStats,
+    DedupForms,
+    PatternSources,
+    PatternedInputs,
+    ExtractForms,
+    FormSizes,
+    Encodings,
+    SampleForms,
 }
 
-type Tally = (i64, i64, i64, i64, i64, i64);
-
-fn identity_tally() -> Tally {
-    (0, 0, 0, 0, 0, 0)
+/// Stored forms come from arbitrary web pages, so warn about and skip the
+/// ones we can't parse rather than giving up on the whole run.
+fn skip_unparseable<T>(result: Result<Vec<T>, Box<dyn Error>>) -> Vec<T> {
+    result.unwrap_or_else(|e| {
+        warn!("Skipping unparseable form: {}", e);
+        Vec::new()
+    })
 }
 
-fn elementwise_sum(l: Tally, r: Tally) -> Tally {
-    (
-        l.0 + r.0,
-        l.1 + r.1,
-        l.2 + r.2,
-        l.3 + r.3,
-        l.4 + r.4,
-        l.5 + r.5,
-    )
+#[derive(Debug, Serialize)]
+struct SummaryReport {
+    nr_warcs: usize,
+    #[serde(flatten)]
+    stats: Stats,
+    unknown_encoding_percent: f64,
+    urls_with_pattern_percent: f64,
+    forms_with_pattern_percent: f64,
+    patterns_per_form: f64,
 }
 
-fn cmd_summarise(warcs: Vec<String>) {
+fn cmd_summarise(dirs: &[FormsDir], json: bool) {
+    let warcs = processed_in(dirs);
     let nr_warcs = warcs.len();
 
-    let (
+    let stats = summarize(
+        warcs
+            .into_par_iter()
+            .flat_map(|(dir, warc)| dir.load(&warc)),
+    );
+
+    if json {
+        let report = SummaryReport {
+            nr_warcs,
+            unknown_encoding_percent: stats.unknown_encoding_percent(),
+            urls_with_pattern_percent: stats.urls_with_pattern_percent(),
+            forms_with_pattern_percent: stats.forms_with_pattern_percent(),
+            patterns_per_form: stats.patterns_per_form(),
+            stats,
+        };
+        outln!(
+            "{}",
+            serde_json::to_string(&report).expect("Unable to serialise summary!")
+        );
+        return;
+    }
+
+    let Stats {
         urls_with_pattern,
         total_urls,
-        forms_w_pattern,
-        total_forms,
+        forms_with_pattern: forms_w_pattern,
         nr_unknown_encoding,
         successful_urls,
-    ) = warcs
-        .into_par_iter()
-        .flat_map(|warc| {
-            let summary = ArchiveSummary::from_file(&to_storage_fn(&warc))?;
-            let urls_w_pattern = summary.urls_with_pattern_forms.len() as i64;
-            let successful = urls_w_pattern + summary.nr_urls_without_patterns;
-            let forms_w_pattern: i64 = summary
-                .urls_with_pattern_forms
-                .iter()
-                .map(|u| u.with_patterns.len() as i64)
-                .sum();
-            let total_urls = successful + summary.nr_unknown_encoding;
-            let total_forms = forms_w_pattern + summary.nr_forms_without_patterns;
-
-            Ok::<_, std::io::Error>((
-                urls_w_pattern,
-                total_urls,
-                forms_w_pattern,
-                total_forms,
-                summary.nr_unknown_encoding,
-                successful,
-            ))
-        })
-        .reduce(identity_tally, elementwise_sum);
+        nr_header_parse_fallback,
+        nr_warcs_without_html,
+        nr_urls_with_iframes,
+        nr_urls_with_only_iframes,
+        ..
+    } = stats;
 
-    println!("Processed {nr_warcs} WARCs with {total_urls} URLs ({successful_urls} OK). Results: ");
-    println!(
+    outln!("Processed {nr_warcs} WARCs with {total_urls} URLs ({successful_urls} OK). Results: ");
+    outln!(
         "Nr URLs with unknown encoding, broken HTML, etc: {nr_unknown_encoding} ({:.4}%)",
-        nr_unknown_encoding as f64 / total_urls as f64
+        stats.unknown_encoding_percent()
     );
-    println!(
+    outln!(
+        "URLs with unparseable HTTP headers (whole response used as body): \
+         {nr_header_parse_fallback}"
+    );
+    if nr_warcs_without_html > 0 {
+        outln!("WARCs without any HTML (media-only, or broken?): {nr_warcs_without_html}");
+    }
+    outln!(
         "URLs with pattern/s: {urls_with_pattern}, ({:.1}%)",
-        100f64 * (urls_with_pattern as f64 / total_urls as f64)
+        stats.urls_with_pattern_percent()
     );
-    println!(
+    outln!(
         "Forms with patterns: {forms_w_pattern} ({:.1}%)",
-        100f64 * (forms_w_pattern as f64 / total_forms as f64)
+        stats.forms_with_pattern_percent()
+    );
+    outln!(
+        "URLs with iframes: {nr_urls_with_iframes}, of which without forms of their own: \
+         {nr_urls_with_only_iframes}"
+    );
+    outln!(
+        "Patterns per form with patterns: {:.2}",
+        stats.patterns_per_form()
     );
 }
 
-fn cmd_forms_with(warcs: Vec<String>) {
-    warcs
-        .par_iter()
-        .flat_map(|warc| ArchiveSummary::from_file(&to_storage_fn(warc)))
-        .flat_map(|summary| summary.urls_with_pattern_forms)
+/// A file name for the `n`th interesting form of `url`. Slashes are escaped
+/// like in stored summary names; long URLs are cut short and given a hash of
+/// the full URL to keep them apart.
+fn form_file_name(url: &str, n: usize) -> String {
+    let escaped = url.replace('/', "!");
+    if escaped.len() <= MAX_URL_IN_FILE_NAME {
+        return format!("{escaped}-{n}.html");
+    }
+    let mut end = MAX_URL_IN_FILE_NAME;
+    while !escaped.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}~{:016x}-{n}.html", &escaped[..end], fnv_hash(url))
+}
+
+/// A form picked by `cmd_sample_forms`, as printed with `--json`.
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+struct SampledForm {
+    #[serde(skip)]
+    key: u64,
+    url: String,
+    form: String,
+}
+
+/// Add `form` to `sample`, keeping only the `n` with the lowest keys.
+fn keep_lowest(sample: &mut BinaryHeap<SampledForm>, form: SampledForm, n: usize) {
+    sample.push(form);
+    if sample.len() > n {
+        sample.pop();
+    }
+}
+
+/// Print `n` of the stored forms, picked uniformly at random in one pass.
+/// Every form gets a key hashed from `seed`, its URL and its place on the
+/// page, and the forms with the `n` lowest keys are kept. Unlike a plain
+/// reservoir, this picks the same forms however the threads interleave, and
+/// `sample_key` doesn't change between Rust releases, so a seed always gives
+/// the same sample.
+fn cmd_sample_forms(dirs: &[FormsDir], n: usize, seed: u64, json: bool) {
+    let sample = all_url_summaries(dirs)
+        .flat_map_iter(|url_summary| {
+            let url = url_summary.url;
+            url_summary
+                .with_patterns
+                .into_iter()
+                .enumerate()
+                .map(move |(i, form)| SampledForm {
+                    key: sample_key(seed, &url, i),
+                    url: url.clone(),
+                    form,
+                })
+        })
+        .fold(BinaryHeap::new, |mut sample, form| {
+            keep_lowest(&mut sample, form, n);
+            sample
+        })
+        .reduce(BinaryHeap::new, |mut l, r| {
+            for form in r {
+                keep_lowest(&mut l, form, n);
+            }
+            l
+        });
+
+    for sampled in sample.into_sorted_vec() {
+        if json {
+            outln!(
+                "{}",
+                serde_json::to_string(&sampled).expect("Unable to serialise form!")
+            );
+        } else {
+            outln!(
+                "URL: {}\n<!-- BEGIN FORM -->\n{}\n<!-- END FORM -->",
+                sampled.url,
+                sampled.form
+            );
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FormSizeReport {
+    nr_forms: usize,
+    mean: f64,
+    median: usize,
+    p90: usize,
+    p99: usize,
+    max: usize,
+}
+
+/// The value below which `percent` of the `sorted` values lie.
+fn percentile(sorted: &[usize], percent: usize) -> usize {
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+/// How big the HTML of forms with patterns is, in bytes. Summaries written
+/// before form sizes were recorded fall back to the stored forms' length.
+fn cmd_form_sizes(dirs: &[FormsDir], json: bool) {
+    let mut sizes: Vec<usize> = all_url_summaries(dirs)
+        .flat_map_iter(|url_summary| {
+            let recorded: Vec<usize> = url_summary
+                .form_details
+                .iter()
+                .map(|details| details.nr_bytes)
+                .collect();
+            url_summary
+                .with_patterns
+                .into_iter()
+                .enumerate()
+                .map(move |(i, form)| match recorded.get(i) {
+                    Some(&nr_bytes) if nr_bytes > 0 => nr_bytes,
+                    _ => form.len(),
+                })
+        })
+        .collect();
+    if sizes.is_empty() {
+        warn!("No forms with patterns found");
+        return;
+    }
+    sizes.par_sort_unstable();
+
+    let report = FormSizeReport {
+        nr_forms: sizes.len(),
+        mean: sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+        median: percentile(&sizes, 50),
+        p90: percentile(&sizes, 90),
+        p99: percentile(&sizes, 99),
+        max: sizes[sizes.len() - 1],
+    };
+    if json {
+        outln!(
+            "{}",
+            serde_json::to_string(&report).expect("Unable to serialise form sizes!")
+        );
+        return;
+    }
+    outln!("Forms with patterns: {}", report.nr_forms);
+    outln!("Mean size: {:.0} bytes", report.mean);
+    outln!(
+        "Median: {} bytes, 90th percentile: {} bytes, 99th percentile: {} bytes, largest: {} bytes",
+        report.median,
+        report.p90,
+        report.p99,
+        report.max
+    );
+}
+
+fn cmd_extract_forms(dirs: &[FormsDir], out_dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+    let nr_written = all_url_summaries(dirs)
+        .map(|url_summary| -> io::Result<usize> {
+            for (n, form) in url_summary.with_patterns.iter().enumerate() {
+                fs::write(out_dir.join(form_file_name(&url_summary.url, n)), form)?;
+            }
+            Ok(url_summary.with_patterns.len())
+        })
+        .try_reduce(|| 0, |a, b| Ok(a + b))?;
+    outln!("Wrote {nr_written} forms to {}", out_dir.display());
+    Ok(())
+}
+
+fn cmd_forms_with(dirs: &[FormsDir]) {
+    all_url_summaries(dirs)
         .flat_map(|form_summary| form_summary.with_patterns)
         .for_each(|form| {
             let stripped_form = form.replace(['\n', '\r'], "");
-            println!("{stripped_form}");
+            outln!("{stripped_form}");
         });
 }
 
-fn cmd_patterns(warcs: Vec<String>) {
-    warcs
-        .par_iter()
-        .flat_map(|warc| ArchiveSummary::from_file(&to_storage_fn(warc)))
-        .flat_map(|summary| summary.urls_with_pattern_forms)
-        .flat_map(|url_summary| url_summary.with_patterns)
-        .flat_map(|form| patterns_in(&form))
-        .for_each(|pattern| {
-            println!("{pattern}");
-        });
+/// Patterns to leave out of the output, as exact strings or as regexes.
+#[derive(Default)]
+struct PatternFilter {
+    exact: HashSet<String>,
+    regexes: Option<RegexSet>,
 }
 
-fn cmd_find_pattern(warcs: Vec<String>) {
-    let stdin = io::stdin();
-    let pattern = stdin.lock().lines().next().unwrap().unwrap();
-    println!("Searching for forms containing {pattern}...");
-    warcs
-        .par_iter()
-        .flat_map(|warc| ArchiveSummary::from_file(&to_storage_fn(warc)))
-        .flat_map(|summary| summary.urls_with_pattern_forms)
+impl PatternFilter {
+    /// Read one pattern per line from `file_name`.
+    fn from_file(file_name: &str, as_regexes: bool) -> Result<Self, Box<dyn Error>> {
+        let lines: Vec<String> = fs::read_to_string(file_name)?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_owned())
+            .collect();
+        if as_regexes {
+            Ok(PatternFilter {
+                regexes: Some(RegexSet::new(lines)?),
+                ..Default::default()
+            })
+        } else {
+            Ok(PatternFilter {
+                exact: lines.into_iter().collect(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn allows(&self, pattern: &str) -> bool {
+        !self.exact.contains(pattern)
+            && !self
+                .regexes
+                .as_ref()
+                .map_or(false, |regexes| regexes.is_match(pattern))
+    }
+}
+
+fn cmd_patterns(
+    dirs: &[FormsDir],
+    with_placeholder: bool,
+    with_inputmode: bool,
+    filter: &PatternFilter,
+) {
+    let forms = all_forms_with_pattern_attrs(dirs);
+
+    if with_placeholder || with_inputmode {
+        forms
+            .flat_map(|(form, attrs)| skip_unparseable(patterned_inputs_with(&form, &attrs)))
+            .filter(|input| filter.allows(&input.pattern))
+            .for_each(|input| {
+                let mut line = input.pattern;
+                if with_placeholder {
+                    line.push('\t');
+                    line.push_str(&input.placeholder.unwrap_or_default());
+                }
+                if with_inputmode {
+                    line.push('\t');
+                    line.push_str(&input.inputmode.unwrap_or_default());
+                }
+                outln!("{line}");
+            });
+    } else {
+        forms
+            .flat_map(|(form, attrs)| skip_unparseable(patterns_in_with(&form, &attrs)))
+            .filter(|(_, pattern)| filter.allows(pattern))
+            .for_each(|(_, pattern)| {
+                outln!("{pattern}");
+            });
+    }
+}
+
+/// How many times each pattern occurs in the summaries in `dirs`.
+fn pattern_counts(dirs: &[FormsDir], filter: &PatternFilter) -> HashMap<String, i64> {
+    all_forms_with_pattern_attrs(dirs)
+        .flat_map(|(form, attrs)| skip_unparseable(patterns_in_with(&form, &attrs)))
+        .filter(|(_, pattern)| filter.allows(pattern))
+        .map(|(_, pattern)| pattern)
+        .fold(HashMap::new, |mut counts, pattern| {
+            *counts.entry(pattern).or_insert(0) += 1;
+            counts
+        })
+        .reduce(HashMap::new, |mut l, r| {
+            for (pattern, count) in r {
+                *l.entry(pattern).or_insert(0) += count;
+            }
+            l
+        })
+}
+
+fn cmd_unique_patterns(dirs: &[FormsDir], filter: &PatternFilter) {
+    let mut unique: Vec<_> = pattern_counts(dirs, filter).into_keys().collect();
+    unique.sort_unstable();
+    for pattern in unique {
+        outln!("{pattern}");
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CountChange {
+    pattern: String,
+    before: i64,
+    after: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct PatternDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<CountChange>,
+}
+
+fn cmd_diff(before: &[FormsDir], after: &[FormsDir], json: bool, filter: &PatternFilter) {
+    let before = pattern_counts(before, filter);
+    let after = pattern_counts(after, filter);
+
+    let mut diff = PatternDiff::default();
+    for (pattern, &count) in after.iter() {
+        match before.get(pattern) {
+            None => diff.added.push(pattern.clone()),
+            Some(&old_count) if old_count != count => diff.changed.push(CountChange {
+                pattern: pattern.clone(),
+                before: old_count,
+                after: count,
+            }),
+            Some(_) => {}
+        }
+    }
+    diff.removed = before
+        .into_keys()
+        .filter(|pattern| !after.contains_key(pattern))
+        .collect();
+    diff.added.sort_unstable();
+    diff.removed.sort_unstable();
+    diff.changed
+        .sort_unstable_by(|l, r| l.pattern.cmp(&r.pattern));
+
+    if json {
+        outln!(
+            "{}",
+            serde_json::to_string(&diff).expect("Unable to serialise diff!")
+        );
+        return;
+    }
+
+    for pattern in diff.added {
+        outln!("+\t{pattern}");
+    }
+    for pattern in diff.removed {
+        outln!("-\t{pattern}");
+    }
+    for CountChange {
+        pattern,
+        before,
+        after,
+    } in diff.changed
+    {
+        outln!("~\t{pattern}\t{before}\t{after}");
+    }
+}
+
+fn cmd_required(dirs: &[FormsDir]) {
+    let (nr_patterned, nr_required) = all_forms_with_pattern_attrs(dirs)
+        .flat_map(|(form, attrs)| skip_unparseable(patterned_inputs_with(&form, &attrs)))
+        .map(|input| (1i64, input.required as i64))
+        .reduce(|| (0, 0), |l, r| (l.0 + r.0, l.1 + r.1));
+
+    outln!(
+        "Patterns on required inputs: {nr_required} of {nr_patterned} ({:.1}%)",
+        100f64 * (nr_required as f64 / nr_patterned as f64)
+    );
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Html,
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// A search result, as printed with `--json`.
+#[derive(Debug, Serialize)]
+struct FoundElement<'a> {
+    url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<String>,
+    #[serde(flatten)]
+    element: ElementMatch,
+}
+
+/// Print each match as a line of JSON, all of a URL's at once.
+fn out_json(url: &str, matches: impl IntoIterator<Item = (Option<String>, ElementMatch)>) {
+    let mut out = String::new();
+    for (action, element) in matches {
+        let found = FoundElement {
+            url,
+            action,
+            element,
+        };
+        out.push_str(&serde_json::to_string(&found).expect("Unable to serialise match!"));
+        out.push('\n');
+    }
+    out!("{out}");
+}
+
+fn cmd_find_pattern(dirs: &[FormsDir], pattern: &str, format: Format, json: bool) {
+    match format {
+        _ if json => {}
+        Format::Text => outln!("Searching for forms containing {pattern}..."),
+        Format::Html => outln!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Forms containing {0}</title>\n</head>\n<body>\n\
+             <h1>Forms containing <code>{0}</code></h1>",
+            escape_html(pattern)
+        ),
+    }
+    all_url_summaries(dirs)
         .filter_map(|url_summary| {
-            let matching_elements: Vec<String> = url_summary
+            let matching_elements: Vec<ElementMatch> = url_summary
                 .with_patterns
                 .into_iter()
-                .flat_map(|form| elements_with(&form, &pattern))
+                .flat_map(|form| skip_unparseable(elements_with(&form, pattern)))
                 .collect();
 
             if matching_elements.is_empty() {
@@ -124,35 +598,482 @@ fn cmd_find_pattern(warcs: Vec<String>) {
             }
         })
         .for_each(|(url, matching_elements)| {
-            println!("URL: {url}");
-            for element in matching_elements.into_iter() {
-                println!("<!-- BEGIN FORM --!>");
-                println!("{element}");
-                println!("<!-- END FORM --!>")
+            if json {
+                out_json(&url, matching_elements.into_iter().map(|e| (None, e)));
+                return;
+            }
+            // Print each URL in one go, so output from different threads
+            // doesn't interleave.
+            let mut out = match format {
+                Format::Text => format!("URL: {url}\n"),
+                Format::Html => {
+                    let url = escape_html(&url);
+                    format!("<section>\n<h2><a href=\"{url}\">{url}</a></h2>\n")
+                }
+            };
+            for ElementMatch { html: element, .. } in matching_elements {
+                match format {
+                    Format::Text => out.push_str(&format!(
+                        "<!-- BEGIN FORM -->\n{element}\n<!-- END FORM -->\n"
+                    )),
+                    Format::Html => out.push_str(&format!("<div>{element}</div>\n")),
+                }
+            }
+            if format == Format::Html {
+                out.push_str("</section>\n");
+            }
+            out!("{out}");
+        });
+
+    if format == Format::Html && !json {
+        outln!("</body>\n</html>");
+    }
+}
+
+/// With `with_url`, print each match on a line of its own, after the URL of
+/// its page and the `action` of its form.
+fn cmd_find_input(dirs: &[FormsDir], queries: &[String], with_url: bool, json: bool) {
+    all_url_summaries(dirs).for_each(|url_summary| {
+        if json {
+            let matches: Vec<_> = url_summary
+                .with_patterns
+                .iter()
+                .flat_map(|form| {
+                    let action = form_action(form);
+                    skip_unparseable(elements_matching_query(form, queries))
+                        .into_iter()
+                        .map(move |element| (action.clone(), element))
+                })
+                .collect();
+            if !matches.is_empty() {
+                out_json(&url_summary.url, matches);
+            }
+            return;
+        }
+        if with_url {
+            let mut out = String::new();
+            for form in url_summary.with_patterns.iter() {
+                let matching_elements = skip_unparseable(elements_matching_query(form, queries));
+                if matching_elements.is_empty() {
+                    continue;
+                }
+                let action = form_action(form).unwrap_or_default();
+                for element in matching_elements {
+                    let element = element.html.replace(['\n', '\r'], "");
+                    out.push_str(&format!("{}\t{action}\t{element}\n", url_summary.url));
+                }
+            }
+            if !out.is_empty() {
+                out!("{out}");
+            }
+            return;
+        }
+
+        let matching_elements: Vec<ElementMatch> = url_summary
+            .with_patterns
+            .iter()
+            .flat_map(|form| skip_unparseable(elements_matching_query(form, queries)))
+            .collect();
+        if matching_elements.is_empty() {
+            return;
+        }
+        // Print each URL in one go, so output from different threads
+        // doesn't interleave.
+        let mut out = format!("URL: {}\n", url_summary.url);
+        for element in matching_elements {
+            out.push_str(&element.html);
+            out.push('\n');
+        }
+        out!("{out}");
+    });
+}
+
+fn cmd_selects(dirs: &[FormsDir], json: bool) {
+    all_url_summaries(dirs)
+        .flat_map(|url_summary| url_summary.with_patterns)
+        .flat_map(|form| skip_unparseable(select_options(&form)))
+        .for_each(|select| {
+            if json {
+                outln!(
+                    "{}",
+                    serde_json::to_string(&select).expect("Unable to serialise select!")
+                );
+            } else {
+                let name = select.name.unwrap_or_default();
+                outln!("{name}\t{}", select.values.join("\t"));
+            }
+        });
+}
+
+fn cmd_tld_stats(dirs: &[FormsDir], json: bool) {
+    let forms_by_suffix = all_url_summaries(dirs)
+        .map(|url_summary| {
+            let suffix = public_suffix(&url_summary.url).unwrap_or_else(|| "(unknown)".to_owned());
+            (suffix, url_summary.with_patterns.len() as u64)
+        })
+        .fold(HashMap::new, |mut counts, (suffix, nr_forms)| {
+            *counts.entry(suffix).or_insert(0) += nr_forms;
+            counts
+        })
+        .reduce(HashMap::new, |mut l, r| {
+            for (suffix, nr_forms) in r {
+                *l.entry(suffix).or_insert(0) += nr_forms;
+            }
+            l
+        });
+
+    if json {
+        outln!(
+            "{}",
+            serde_json::to_string(&forms_by_suffix).expect("Unable to serialise TLD stats!")
+        );
+        return;
+    }
+
+    let mut forms_by_suffix: Vec<_> = forms_by_suffix.into_iter().collect();
+    forms_by_suffix.sort_unstable_by(|l, r| r.1.cmp(&l.1).then_with(|| l.0.cmp(&r.0)));
+    for (suffix, nr_forms) in forms_by_suffix {
+        outln!("{suffix}\t{nr_forms}");
+    }
+}
+
+/// How many pages were decoded with each encoding, most common first.
+fn cmd_encodings(dirs: &[FormsDir], json: bool) {
+    let encodings = processed_in(dirs)
+        .into_par_iter()
+        .flat_map(|(dir, warc)| dir.load(&warc))
+        .map(|summary| summary.encodings)
+        .reduce(HashMap::new, |mut l, r| {
+            for (encoding, count) in r {
+                *l.entry(encoding).or_insert(0) += count;
+            }
+            l
+        });
+
+    if json {
+        outln!(
+            "{}",
+            serde_json::to_string(&encodings).expect("Unable to serialise encodings!")
+        );
+        return;
+    }
+
+    let total: u64 = encodings.values().sum();
+    let mut encodings: Vec<_> = encodings.into_iter().collect();
+    encodings.sort_unstable_by(|l, r| r.1.cmp(&l.1).then_with(|| l.0.cmp(&r.0)));
+    for (encoding, count) in encodings {
+        outln!(
+            "{encoding}\t{count}\t{:.1}%",
+            100.0 * count as f64 / total as f64
+        );
+    }
+}
+
+/// Read a pattern or query from the first line of stdin, for when none was
+/// given on the command line.
+fn read_query() -> Result<String, Box<dyn Error>> {
+    Ok(io::stdin()
+        .lock()
+        .lines()
+        .next()
+        .ok_or("Expected a query on stdin")??)
+}
+
+/// Whitespace differences between copies of the same template shouldn't
+/// make them count as different forms.
+fn normalise_form(form: &str) -> String {
+    form.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicateForm {
+    count: usize,
+    form: String,
+    urls: Vec<String>,
+}
+
+fn cmd_dedup_forms(dirs: &[FormsDir], json: bool) {
+    let by_form = all_url_summaries(dirs)
+        .flat_map_iter(|url_summary| {
+            let url = url_summary.url;
+            url_summary
+                .with_patterns
+                .into_iter()
+                .map(move |form| (url.clone(), normalise_form(&form)))
+        })
+        .fold(
+            HashMap::new,
+            |mut by_form: HashMap<String, Vec<String>>, (url, form)| {
+                by_form.entry(form).or_default().push(url);
+                by_form
+            },
+        )
+        .reduce(HashMap::new, |mut l, r| {
+            for (form, urls) in r {
+                l.entry(form).or_default().extend(urls);
             }
+            l
         });
+
+    let mut duplicates: Vec<DuplicateForm> = by_form
+        .into_iter()
+        .map(|(form, mut urls)| {
+            urls.sort_unstable();
+            DuplicateForm {
+                count: urls.len(),
+                form,
+                urls,
+            }
+        })
+        .collect();
+    duplicates.sort_unstable_by(|l, r| r.count.cmp(&l.count).then_with(|| l.form.cmp(&r.form)));
+
+    for duplicate in duplicates {
+        if json {
+            outln!(
+                "{}",
+                serde_json::to_string(&duplicate).expect("Unable to serialise form!")
+            );
+            continue;
+        }
+        outln!("{}\t{}", duplicate.count, duplicate.form);
+        for url in duplicate.urls {
+            outln!("\t{url}");
+        }
+    }
+}
+
+fn cmd_pattern_sources(dirs: &[FormsDir], json: bool, filter: &PatternFilter) {
+    let by_source = all_forms_with_pattern_attrs(dirs)
+        .flat_map(|(form, attrs)| skip_unparseable(patterns_in_with(&form, &attrs)))
+        .filter(|(_, pattern)| filter.allows(pattern))
+        .fold(BTreeMap::new, |mut counts, (source, _)| {
+            *counts.entry(source).or_insert(0u64) += 1;
+            counts
+        })
+        .reduce(BTreeMap::new, |mut l, r| {
+            for (source, count) in r {
+                *l.entry(source).or_insert(0) += count;
+            }
+            l
+        });
+
+    if json {
+        outln!(
+            "{}",
+            serde_json::to_string(&by_source).expect("Unable to serialise pattern sources!")
+        );
+        return;
+    }
+    for (source, count) in by_source {
+        outln!("{source}\t{count}");
+    }
 }
 
+/// How many interesting forms have how many patterned inputs. Summaries
+/// written before this was recorded have no per-form details and are
+/// skipped.
+fn cmd_patterned_inputs(dirs: &[FormsDir], json: bool) {
+    let (histogram, nr_without_details) = all_url_summaries(dirs)
+        .fold(
+            || (BTreeMap::new(), 0u64),
+            |(mut histogram, mut nr_without_details), url_summary| {
+                if url_summary.form_details.is_empty() {
+                    nr_without_details += url_summary.with_patterns.len() as u64;
+                }
+                for details in url_summary.form_details {
+                    *histogram.entry(details.nr_patterned_inputs).or_insert(0u64) += 1;
+                }
+                (histogram, nr_without_details)
+            },
+        )
+        .reduce(
+            || (BTreeMap::new(), 0),
+            |(mut l, l_without), (r, r_without)| {
+                for (nr_inputs, count) in r {
+                    *l.entry(nr_inputs).or_insert(0) += count;
+                }
+                (l, l_without + r_without)
+            },
+        );
+
+    if nr_without_details > 0 {
+        warn!(
+            "Skipped {} forms from summaries without per-form details",
+            nr_without_details
+        );
+    }
+
+    if json {
+        outln!(
+            "{}",
+            serde_json::to_string(&histogram).expect("Unable to serialise histogram!")
+        );
+        return;
+    }
+    for (nr_inputs, count) in histogram {
+        outln!("{nr_inputs}\t{count}");
+    }
+}
+
+const USAGE: &str = "usage: cc-analyse [-q | -v | -vv] [--threads <n>] [--json] [--format text|html] [--with-placeholder] [--with-inputmode] [--with-url] \
+[--exclude-patterns-from <file> [--exclude-regex]] [--output <file to append to>] [--dir <forms dir>] [--seed <n>] \
+summary | patterns | unique-patterns | forms | find-pattern [pattern] | find-input [query]... | selects | tld-stats | encodings | dedup-forms | pattern-sources | patterned-inputs | extract-forms <out dir> | form-sizes | sample-forms <n> | required \
+| merge <dir>... | diff <before dir> <after dir>";
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let subcommand = std::env::args()
-        .nth(1)
+    let mut json = false;
+    let mut format = Format::Text;
+    let mut with_placeholder = false;
+    let mut with_inputmode = false;
+    let mut with_url = false;
+    let mut exclude_from = None;
+    let mut exclude_regex = false;
+    let mut threads = None;
+    let mut verbosity = 0;
+    let mut seed = 0;
+    let mut output = None;
+    let mut dir = FormsDir::default();
+    let mut args = Vec::new();
+    let mut all_args = std::env::args().skip(1);
+    while let Some(arg) = all_args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-q" | "--quiet" => verbosity -= 1,
+            "-v" | "--verbose" => verbosity += 1,
+            "-vv" => verbosity += 2,
+            "--with-placeholder" => with_placeholder = true,
+            "--with-inputmode" => with_inputmode = true,
+            "--with-url" => with_url = true,
+            "--exclude-patterns-from" => exclude_from = Some(all_args.next().ok_or(USAGE)?),
+            "--exclude-regex" => exclude_regex = true,
+            "--output" => output = Some(all_args.next().ok_or(USAGE)?),
+            "--dir" => dir = FormsDir::new(all_args.next().ok_or(USAGE)?),
+            "--threads" => {
+                threads = Some(all_args.next().ok_or(USAGE)?.parse::<usize>()?);
+            }
+            "--seed" => seed = all_args.next().ok_or(USAGE)?.parse::<u64>()?,
+            "--format" => {
+                format = match all_args.next().as_deref() {
+                    Some("text") => Format::Text,
+                    Some("html") => Format::Html,
+                    _ => return Err(USAGE.into()),
+                }
+            }
+            _ => args.push(arg),
+        }
+    }
+
+    init_logger(verbosity);
+
+    if let Some(file_name) = output {
+        *OUTPUT.lock().unwrap() = Output::append_to(&file_name)?;
+    }
+
+    if let Some(threads) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+
+    let filter = match exclude_from {
+        Some(file_name) => PatternFilter::from_file(&file_name, exclude_regex)?,
+        None => PatternFilter::default(),
+    };
+
+    let mut args = args.into_iter();
+    let subcommand = args
+        .next()
         .and_then(|arg| match arg.as_str() {
             "summary" => Some(Cmd::Summary),
             "patterns" => Some(Cmd::Patterns),
             "forms" => Some(Cmd::Forms),
             "find-pattern" => Some(Cmd::FindPattern),
+            "merge" => Some(Cmd::Merge),
+            "required" => Some(Cmd::Required),
+            "unique-patterns" => Some(Cmd::UniquePatterns),
+            "find-input" => Some(Cmd::FindInput),
+            "selects" => Some(Cmd::Selects),
+            "diff" => Some(Cmd::Diff),
+            "tld-stats" => Some(Cmd::TldStats),
+            "encodings" => Some(Cmd::Encodings),
+            "dedup-forms" => Some(Cmd::DedupForms),
+            "pattern-sources" => Some(Cmd::PatternSources),
+            "patterned-inputs" => Some(Cmd::PatternedInputs),
+            "extract-forms" => Some(Cmd::ExtractForms),
+            "form-sizes" => Some(Cmd::FormSizes),
+            "sample-forms" => Some(Cmd::SampleForms),
             _ => None,
         })
-        .ok_or("usage: cc-get summary | patterns | forms")?;
+        .ok_or(USAGE)?;
 
-    let warcs: Vec<_> = processed_warcs();
+    let mut queries = Vec::new();
+    let mut out_dir = None;
+    let mut sample_size = 0;
+    let dirs: Vec<FormsDir> = match subcommand {
+        Cmd::Merge => args.map(FormsDir::new).collect(),
+        Cmd::Diff => {
+            let dirs: Vec<FormsDir> = args.map(FormsDir::new).collect();
+            if dirs.len() != 2 {
+                return Err(USAGE.into());
+            }
+            dirs
+        }
+        Cmd::FindPattern | Cmd::FindInput => {
+            queries.extend(args);
+            if queries.is_empty() {
+                queries.push(read_query()?);
+            }
+            if matches!(subcommand, Cmd::FindPattern) && queries.len() != 1 {
+                return Err(USAGE.into());
+            }
+            vec![dir.clone()]
+        }
+        Cmd::ExtractForms => {
+            out_dir = args.next();
+            if out_dir.is_none() || args.next().is_some() {
+                return Err(USAGE.into());
+            }
+            vec![dir.clone()]
+        }
+        Cmd::SampleForms => {
+            sample_size = args.next().ok_or(USAGE)?.parse()?;
+            if args.next().is_some() {
+                return Err(USAGE.into());
+            }
+            vec![dir.clone()]
+        }
+        _ => vec![dir.clone()],
+    };
+    if dirs.is_empty() {
+        return Err(USAGE.into());
+    }
+    for dir in &dirs {
+        dir.check()?;
+    }
 
     match subcommand {
-        Cmd::Summary => cmd_summarise(warcs),
-        Cmd::Patterns => cmd_patterns(warcs),
-        Cmd::Forms => cmd_forms_with(warcs),
-        Cmd::FindPattern => cmd_find_pattern(warcs),
+        Cmd::Summary | Cmd::Merge => cmd_summarise(&dirs, json),
+        Cmd::Patterns => cmd_patterns(&dirs, with_placeholder, with_inputmode, &filter),
+        Cmd::Forms => cmd_forms_with(&dirs),
+        Cmd::FindPattern => cmd_find_pattern(&dirs, &queries[0], format, json),
+        Cmd::Required => cmd_required(&dirs),
+        Cmd::UniquePatterns => cmd_unique_patterns(&dirs, &filter),
+        Cmd::FindInput => cmd_find_input(&dirs, &queries, with_url, json),
+        Cmd::Selects => cmd_selects(&dirs, json),
+        Cmd::TldStats => cmd_tld_stats(&dirs, json),
+        Cmd::Encodings => cmd_encodings(&dirs, json),
+        Cmd::DedupForms => cmd_dedup_forms(&dirs, json),
+        Cmd::PatternSources => cmd_pattern_sources(&dirs, json, &filter),
+        Cmd::PatternedInputs => cmd_patterned_inputs(&dirs, json),
+        Cmd::FormSizes => cmd_form_sizes(&dirs, json),
+        Cmd::SampleForms => cmd_sample_forms(&dirs, sample_size, seed, json),
+        Cmd::ExtractForms => {
+            cmd_extract_forms(&dirs, Path::new(out_dir.as_deref().unwrap_or_default()))?
+        }
+        Cmd::Diff => cmd_diff(&dirs[..1], &dirs[1..], json, &filter),
     }
 
+    OUTPUT.lock().unwrap().flush();
     Ok(())
 }
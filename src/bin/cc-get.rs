@@ -1,43 +1,488 @@
+use chrono::{DateTime, Utc};
 use flate2::read::MultiGzDecoder;
-use log::info;
+use log::{info, warn};
 use std::collections::HashSet;
+use std::env;
 use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
-use bo_cc::{process_warcs, processed_warcs, Client};
+use bo_cc::{
+    init_logger, process_urls, process_warcs_with_progress, targeted_key, Client, ClientOptions,
+    FormsDir, ProcessingOptions, RunStats, WarcProgress,
+};
 
+/// All the WARCs of an archive. The paths file is small, so it is read in
+/// full; this also tells us how many WARCs there are up front.
 fn get_warcs(
     client: &mut Client,
-    warcs_present: HashSet<String>,
     archive: &str,
-) -> Result<impl Iterator<Item = String>, reqwest::Error> {
-    let gz = BufReader::new(
-        client
-            .get(&format!("crawl-data/{}/warc.paths.gz", archive))?
-            .error_for_status()?,
-    );
+    dir: &FormsDir,
+) -> Result<Vec<String>, reqwest::Error> {
+    let path = format!("crawl-data/{}/warc.paths.gz", archive);
+    let gz = client.get_bytes_cached(&path, &dir.cache_fn(&path))?;
 
-    Ok(BufReader::new(MultiGzDecoder::new(gz))
+    let warcs: Vec<String> = BufReader::new(MultiGzDecoder::new(io::Cursor::new(gz)))
         .lines()
         .flatten()
-        .filter(move |u| !warcs_present.contains(u)))
+        .collect();
+    info!("Archive {} has {} WARCs", archive, warcs.len());
+    Ok(warcs)
+}
+
+const USAGE: &str =
+    "Usage: cc-get [-q | -v | -vv] [--durable] [--pretty] [--no-index-rewrite] [--no-retry-pass] [--print-config] [--emit-progress-json] [--lossy-decode] [--keep-partial] [--only-2xx] [--no-tld-hint] [--collapse-duplicate-forms] [--detect-amp] [--record-errors] [--dir <output dir>] \
+[--shard-index <i> --shard-count <n>] [--threads <n>] [--max-concurrent-warcs <n>] [--heartbeat-secs <s, 0 to disable>] [--pool-size <n>] \
+[--user-agent <agent>] [--contact <email or URL>] [--min-delay-secs <s>] [--no-rate-limit] [--base-url <URL>] \
+[--since <RFC3339 date>] [--until <RFC3339 date>] [--pattern-attr <attribute>]... [--max-body-bytes <n>] \
+[--sample <probability> [--sample-seed <n>]] [--max-error-rate <fraction>] \
+[--urls-from <file, or - for stdin> [--index-url <URL>]] [--resume-from <WARC path>] \
+<archive, e.g. CC-MAIN-2023-40, or - to read WARC paths from stdin>...
+
+The environment variables FORMS_DIR, CC_ARCHIVE and CC_BASE_URL are used
+when --dir, the archive or --base-url are not given.
+
+--pattern-attr may be repeated. Only forms with inputs carrying one of the
+given attributes are kept; without it, pattern, data-val-regex-pattern and
+ng-pattern are used. The attributes are recorded in the summaries, so
+cc-analyse reads patterns from the same ones.
+
+--max-concurrent-warcs bounds how many WARCs are held in memory at once,
+while --threads still sets how many records are processed in parallel.
+
+--resume-from skips the WARCs listed before the given one, on top of those
+already in the index. Within a shard, only its own WARCs are looked at.
+
+--urls-from fetches only the listed pages, looking them up in the CDX index
+of the (single) archive and downloading just their records. They are stored
+as one summary, once every page could be fetched.
+
+--emit-progress-json prints a line of JSON to stdout for each WARC as it is
+done, with its URL, its outcome (ok, skipped or failed) and its main counts.";
+
+/// Environment variable with the archive to process, if none is given.
+const ARCHIVE_VAR: &str = "CC_ARCHIVE";
+/// How long to wait before giving the WARCs that failed a second chance.
+const RETRY_PASS_DELAY: Duration = Duration::from_secs(60);
+
+struct Args {
+    archives: Vec<String>,
+    options: ProcessingOptions,
+    client_options: ClientOptions,
+    /// Only process every `shard_count`th WARC, starting at `shard_index`.
+    shard: Option<(usize, usize)>,
+    threads: Option<usize>,
+    /// Below zero for quieter logging, above for more.
+    verbosity: i32,
+    /// Try WARCs that failed for possibly temporary reasons once more at the
+    /// end of the run.
+    retry_failed: bool,
+    /// Print the settings the run would use, and do nothing else.
+    print_config: bool,
+    /// Print a line of JSON to stdout as each WARC is done, for following a
+    /// run as it goes.
+    emit_progress_json: bool,
+    /// Only fetch the pages listed in this file, or on stdin for `-`, using
+    /// the archive's CDX index.
+    urls_from: Option<String>,
+    /// Skip the WARCs listed before this one.
+    resume_from: Option<String>,
+}
+
+fn value_of<T: FromStr>(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+) -> Result<T, Box<dyn Error>>
+where
+    T::Err: Display,
+{
+    let value = args
+        .next()
+        .ok_or_else(|| format!("{flag} needs a value. {USAGE}"))?;
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value {value} for {flag}: {e}").into())
+}
+
+fn date_of(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    let value: String = value_of(args, flag)?;
+    DateTime::parse_from_rfc3339(&value)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|e| format!("Invalid date {value} for {flag}: {e}").into())
+}
+
+impl Args {
+    fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut archives = Vec::new();
+        let mut options = ProcessingOptions::default();
+        let mut client_options = ClientOptions::default();
+        let mut shard_index = None;
+        let mut shard_count = None;
+        let mut threads = None;
+        let mut verbosity = 0;
+        let mut pattern_attrs = Vec::new();
+        let mut retry_failed = true;
+        let mut print_config = false;
+        let mut emit_progress_json = false;
+        let mut urls_from = None;
+        let mut resume_from = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-q" | "--quiet" => verbosity -= 1,
+                "-v" | "--verbose" => verbosity += 1,
+                "-vv" => verbosity += 2,
+                "--durable" => options.writer.durable = true,
+                "--pretty" => options.writer.pretty = true,
+                "--no-index-rewrite" => options.writer.skip_index_rewrite = true,
+                "--no-retry-pass" => retry_failed = false,
+                "--print-config" => print_config = true,
+                "--emit-progress-json" => emit_progress_json = true,
+                "--lossy-decode" => options.lossy_decode = true,
+                "--keep-partial" => options.keep_partial = true,
+                "--only-2xx" => options.only_success = true,
+                "--no-tld-hint" => options.tld_hint = false,
+                "--collapse-duplicate-forms" => options.collapse_duplicate_forms = true,
+                "--detect-amp" => options.detect_amp = true,
+                "--record-errors" => options.record_errors = true,
+                "--dir" => options.writer.dir = FormsDir::new(value_of::<String>(&mut args, &arg)?),
+                "--heartbeat-secs" => {
+                    options.heartbeat_after = match value_of(&mut args, &arg)? {
+                        0 => None,
+                        s => Some(Duration::from_secs(s)),
+                    }
+                }
+                "--pool-size" => {
+                    client_options.pool_max_idle_per_host = Some(value_of(&mut args, &arg)?)
+                }
+                "--since" => options.since = Some(date_of(&mut args, &arg)?),
+                "--until" => options.until = Some(date_of(&mut args, &arg)?),
+                "--user-agent" => client_options.user_agent = Some(value_of(&mut args, &arg)?),
+                "--base-url" => client_options.base_url = Some(value_of(&mut args, &arg)?),
+                "--min-delay-secs" => {
+                    let secs: f64 = value_of(&mut args, &arg)?;
+                    client_options.min_delay = Duration::try_from_secs_f64(secs)
+                        .map_err(|e| format!("Invalid value {secs} for {arg}: {e}"))?
+                }
+                "--no-rate-limit" => client_options.unlimited = true,
+                "--contact" => client_options.contact = Some(value_of(&mut args, &arg)?),
+                "--sample" => options.sample = Some(value_of(&mut args, &arg)?),
+                "--max-error-rate" => options.max_error_rate = Some(value_of(&mut args, &arg)?),
+                "--sample-seed" => options.sample_seed = value_of(&mut args, &arg)?,
+                "--max-body-bytes" => options.max_body_bytes = Some(value_of(&mut args, &arg)?),
+                "--pattern-attr" => pattern_attrs.push(value_of(&mut args, &arg)?),
+                "--urls-from" => urls_from = Some(value_of(&mut args, &arg)?),
+                "--resume-from" => resume_from = Some(value_of(&mut args, &arg)?),
+                "--index-url" => client_options.index_url = Some(value_of(&mut args, &arg)?),
+                "--threads" => threads = Some(value_of(&mut args, &arg)?),
+                "--max-concurrent-warcs" => {
+                    options.max_concurrent_warcs = Some(value_of(&mut args, &arg)?)
+                }
+                "--shard-index" => shard_index = Some(value_of(&mut args, &arg)?),
+                "--shard-count" => shard_count = Some(value_of(&mut args, &arg)?),
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option {arg}. {USAGE}").into())
+                }
+                _ => archives.push(arg),
+            }
+        }
+
+        if let (Some(since), Some(until)) = (options.since, options.until) {
+            if since >= until {
+                return Err("--since must be before --until".into());
+            }
+        }
+
+        if let Some(p) = options.sample {
+            if !(p > 0.0 && p <= 1.0) {
+                return Err("--sample must be above 0 and at most 1".into());
+            }
+        }
+
+        if !pattern_attrs.is_empty() {
+            options.pattern_attrs = pattern_attrs;
+        }
+
+        client_options.pause_file = Some(options.writer.dir.pause_fn());
+
+        let shard =
+            match (shard_index, shard_count) {
+                (None, None) => None,
+                (Some(index), Some(count)) if index < count => Some((index, count)),
+                _ => return Err(
+                    "--shard-index and --shard-count must be given together, with index < count"
+                        .into(),
+                ),
+            };
+
+        if archives.is_empty() {
+            archives.extend(env::var(ARCHIVE_VAR).ok());
+        }
+        if archives.is_empty() {
+            return Err(USAGE.into());
+        }
+        if urls_from.is_some() && (archives.len() != 1 || archives[0] == "-") {
+            return Err("--urls-from needs exactly one archive to look the URLs up in".into());
+        }
+
+        Ok(Args {
+            archives,
+            options,
+            client_options,
+            shard,
+            threads,
+            verbosity,
+            retry_failed,
+            print_config,
+            emit_progress_json,
+            urls_from,
+            resume_from,
+        })
+    }
+
+    /// Every setting of the run, with defaults and the environment filled in.
+    fn config(&self) -> serde_json::Value {
+        let options = &self.options;
+        let client = &self.client_options;
+        let date = |date: Option<DateTime<Utc>>| date.map(|date| date.to_rfc3339());
+        serde_json::json!({
+            "archives": self.archives,
+            "dir": options.writer.dir.path().display().to_string(),
+            "codec": options.writer.codec.extension(),
+            "durable": options.writer.durable,
+            "pretty": options.writer.pretty,
+            "skip_index_rewrite": options.writer.skip_index_rewrite,
+            "threads": self.threads.unwrap_or_else(rayon::current_num_threads),
+            "max_concurrent_warcs": options.max_concurrent_warcs,
+            "shard": self.shard,
+            "retry_failed": self.retry_failed,
+            "base_url": client.base_url(),
+            "index_url": client.index_url(),
+            "urls_from": self.urls_from,
+            "resume_from": self.resume_from,
+            "user_agent": client.user_agent(),
+            "pool_size": client.pool_max_idle_per_host,
+            "min_delay_secs": client.min_delay.as_secs_f64(),
+            "rate_limited": !client.unlimited,
+            "heartbeat_secs": options.heartbeat_after.map(|after| after.as_secs()),
+            "since": date(options.since),
+            "until": date(options.until),
+            "keep_partial": options.keep_partial,
+            "only_2xx": options.only_success,
+            "tld_hint": options.tld_hint,
+            "record_errors": options.record_errors,
+            "pattern_attrs": options.pattern_attrs,
+            "max_body_bytes": options.max_body_bytes,
+            "sample": options.sample,
+            "sample_seed": options.sample_seed,
+            "max_error_rate": options.max_error_rate,
+            "collapse_duplicate_forms": options.collapse_duplicate_forms,
+            "detect_amp": options.detect_amp,
+            "emit_progress_json": self.emit_progress_json,
+            "lossy_decode": options.lossy_decode,
+        })
+    }
+}
+
+/// Fetch only the pages listed in `urls_from`, looking them up in the CDX
+/// index of `archive`.
+fn fetch_targeted(
+    urls_from: &str,
+    archive: &str,
+    client: Client,
+    options: ProcessingOptions,
+) -> Result<(), Box<dyn Error>> {
+    let lines: Vec<String> = if urls_from == "-" {
+        io::stdin().lines().collect::<Result<_, _>>()?
+    } else {
+        BufReader::new(File::open(urls_from)?)
+            .lines()
+            .collect::<Result<_, _>>()?
+    };
+    let urls: Vec<String> = lines
+        .into_iter()
+        .map(|line| line.trim().to_owned())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    let key = targeted_key(archive, &urls);
+    if options.writer.dir.has_summary(&key) {
+        info!("These {} URLs were already fetched, as {}", urls.len(), key);
+        return Ok(());
+    }
+    info!("Fetching {} URLs from {}", urls.len(), archive);
+    let stats = process_urls(urls, archive, client, options.clone());
+    info!(
+        "Shutting down... {} URLs fetched, {} failed",
+        stats.succeeded, stats.failed
+    );
+    options.writer.dir.write_run_report(&stats)?;
+    if stats.failed > 0 {
+        return Err(format!("{} URLs failed", stats.failed).into());
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+    let args = Args::parse()?;
+    if args.print_config {
+        println!("{}", serde_json::to_string_pretty(&args.config())?);
+        return Ok(());
+    }
+    let Args {
+        archives,
+        options,
+        client_options,
+        shard,
+        threads,
+        verbosity,
+        retry_failed,
+        emit_progress_json,
+        urls_from,
+        mut resume_from,
+        ..
+    } = args;
+
+    init_logger(verbosity);
+
+    if let Some(threads) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+
+    // Find out about an unusable output directory before downloading anything.
+    options.writer.dir.create()?;
+
+    let mut client = Client::with_options(client_options);
+    let on_done = |progress: &WarcProgress| {
+        if emit_progress_json {
+            println!(
+                "{}",
+                serde_json::to_string(progress).expect("Unable to serialise progress!")
+            );
+        }
+    };
+
+    if let Some(urls_from) = urls_from {
+        return fetch_targeted(&urls_from, &archives[0], client, options);
+    }
+
+    // Also holds everything queued so far, so WARCs listed under several
+    // archives are only processed once.
+    // Indexed WARCs whose summary is missing are dropped from the index by
+    // the writer, so process them again.
+    let mut seen: HashSet<String> = options
+        .writer
+        .dir
+        .processed_warcs()?
+        .into_iter()
+        .filter(|warc| options.writer.dir.has_summary(warc))
+        .collect();
+    let (shard_index, shard_count) = shard.unwrap_or((0, 1));
+    let mut stats = RunStats::default();
+
+    for archive in archives {
+        info!("Processing archive {}", archive);
+        let all_warcs: Box<dyn Iterator<Item = String>> = if archive == "-" {
+            Box::new(
+                io::stdin()
+                    .lines()
+                    .flatten()
+                    .map(|line| line.trim().to_owned())
+                    .filter(|line| !line.is_empty()),
+            )
+        } else {
+            Box::new(get_warcs(&mut client, &archive, &options.writer.dir)?.into_iter())
+        };
 
-    let archive = std::env::args()
-        .nth(1)
-        .ok_or("Usage: cc-get <archive, e.g. CC-MAIN-2023-40>")?;
+        // Skip after numbering the WARCs, so they stay in the same shards.
+        let mut nr_before_resume = 0;
+        let (already_processed, warc_urls): (Vec<_>, Vec<_>) = all_warcs
+            .enumerate()
+            .filter(|(i, _)| i % shard_count == shard_index)
+            .map(|(_, warc_url)| warc_url)
+            .skip_while(|warc_url| match &resume_from {
+                Some(first) if first != warc_url => {
+                    nr_before_resume += 1;
+                    true
+                }
+                Some(_) => {
+                    info!("Resuming from {}", warc_url);
+                    resume_from = None;
+                    false
+                }
+                None => false,
+            })
+            .partition(|u| !seen.insert(u.clone()));
 
-    let mut client = bo_cc::Client::new();
+        info!(
+            "{} WARCs to process, {} already done, {} before the resume point",
+            warc_urls.len(),
+            already_processed.len(),
+            nr_before_resume
+        );
+        stats = stats
+            .merge(process_warcs_with_progress(
+                warc_urls,
+                client.clone(),
+                options.clone(),
+                on_done,
+            ))
+            .merge(RunStats {
+                skipped: (already_processed.len() + nr_before_resume) as u64,
+                ..Default::default()
+            });
+        if stats.aborted {
+            break;
+        }
+    }
 
-    let seen: HashSet<String> = processed_warcs().into_iter().collect();
-    let warc_urls = get_warcs(&mut client, seen, &archive)?.collect();
+    if let Some(first) = resume_from {
+        warn!("Never reached {} to resume from", first);
+    }
 
-    process_warcs(warc_urls, client);
+    if retry_failed && !stats.aborted {
+        let (retryable, permanent): (Vec<_>, Vec<_>) = std::mem::take(&mut stats.failed_warcs)
+            .into_iter()
+            .partition(|warc| warc.retryable);
+        stats.failed_warcs = permanent;
+        if !retryable.is_empty() {
+            info!(
+                "Trying {} failed WARCs again in {}s",
+                retryable.len(),
+                RETRY_PASS_DELAY.as_secs()
+            );
+            thread::sleep(RETRY_PASS_DELAY);
+            stats.failed -= retryable.len() as u64;
+            stats = stats.merge(process_warcs_with_progress(
+                retryable.into_iter().map(|warc| warc.url).collect(),
+                client.clone(),
+                options.clone(),
+                on_done,
+            ));
+        }
+    }
+    for warc in &stats.failed_warcs {
+        warn!("Giving up on {}: {}", warc.url, warc.reason);
+    }
 
-    info!("Shutting down...");
+    info!("Shutting down... {}", stats);
+    options.writer.dir.write_run_report(&stats)?;
+    if stats.aborted {
+        return Err("Too many pages could not be decoded".into());
+    }
+    if stats.failed > 0 {
+        return Err(format!("{} WARCs failed", stats.failed).into());
+    }
     Ok(())
 }
@@ -1,30 +1,43 @@
 use std::{
     borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
+    fmt,
     io::{self, BufReader, ErrorKind},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         mpsc::{self, Receiver, SendError},
         Arc, Mutex,
     },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
-const COMPRESSION_LEVEL: u32 = 6;
+const XZ_LEVEL: u32 = 6;
+const ZSTD_LEVEL: i32 = 9;
 use serde::{Deserialize, Serialize};
 
 use chardetng::EncodingDetector;
+use chrono::{DateTime, Utc};
 use encoding_rs::Encoding;
-use flate2::read::MultiGzDecoder;
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
 use httparse::Header;
 use log::{error, info, trace, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::iter::{IntoParallelIterator, ParallelBridge};
 use rayon::prelude::ParallelIterator;
 use reqwest::blocking::{ClientBuilder, Response};
-use rust_warc::{CaseString, WarcReader};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+    RANGE,
+};
+use reqwest::StatusCode;
+use rust_warc::{CaseString, WarcReader, WarcRecord};
+use std::env;
 use std::fs;
 use std::io::prelude::*;
 use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use url::Url;
 use xz2::{read::XzDecoder, write::XzEncoder};
 #[macro_use]
 extern crate lazy_static;
@@ -32,92 +45,605 @@ extern crate lazy_static;
 type UrlAndSummary = (String, ArchiveSummary);
 
 const WRITE_BACKLOG: usize = 32;
+pub const DEFAULT_FORMS_DIR: &str = "forms.d";
 pub const COOLDOWN_S: f32 = 2.0;
 pub const INITIAL_WAIT: u64 = 0;
 pub const MAX_WAIT: u64 = 30;
+pub const DEFAULT_HEARTBEAT_S: u64 = 300;
+const PAUSE_POLL_S: u64 = 5;
+const WAIT_POLL_MS: u64 = 50;
+/// How many per-record errors to keep for each WARC.
+const MAX_RECORD_ERRORS: usize = 100;
+/// WARCs with at least this many records but no HTML are reported.
+const MIN_RECORDS_FOR_HTML: i64 = 100;
+/// Pages to see before `ProcessingOptions::max_error_rate` is enforced, so a
+/// bad first WARC doesn't end the run.
+const MIN_PAGES_FOR_ERROR_RATE: u64 = 10_000;
+/// Environment variable that overrides the user agent.
+pub const USER_AGENT_VAR: &str = "BO_CC_USER_AGENT";
+/// Environment variable that overrides the default output directory.
+pub const FORMS_DIR_VAR: &str = "FORMS_DIR";
+/// Environment variable that overrides where CommonCrawl data is fetched from.
+pub const BASE_URL_VAR: &str = "CC_BASE_URL";
+pub const DEFAULT_BASE_URL: &str = "https://data.commoncrawl.org/";
+/// Environment variable with where to look up URLs in the CDX index.
+pub const INDEX_URL_VAR: &str = "CC_INDEX_URL";
+pub const DEFAULT_INDEX_URL: &str = "https://index.commoncrawl.org/";
+/// The attributes of `<input>` elements that hold validation patterns, one
+/// for each `PatternSource`.
+pub const INTERESTING_ATTRS: [&str; PatternSource::ALL.len()] = {
+    let mut attrs = [""; PatternSource::ALL.len()];
+    let mut i = 0;
+    while i < attrs.len() {
+        attrs[i] = PatternSource::ALL[i].attribute();
+        i += 1;
+    }
+    attrs
+};
+/// Attributes of `<form>` elements that only amp-form uses.
+const AMP_FORM_ATTRS: &[&str] = &["action-xhr", "custom-validation-reporting"];
+
+/// `INTERESTING_ATTRS`, owned, for configuring extraction.
+pub fn default_pattern_attrs() -> Vec<String> {
+    INTERESTING_ATTRS
+        .iter()
+        .map(|attr| attr.to_string())
+        .collect()
+}
+
 lazy_static! {
     static ref WARC_TYPE: CaseString = CaseString::from("WARC-Type");
+    static ref WARC_DATE: CaseString = CaseString::from("WARC-Date");
+    /// Selects inputs with any of the interesting attributes
+    static ref INTERESTING_INPUTS_QUERY: String = INTERESTING_ATTRS
+        .iter()
+        .map(|attr| format!("input[{attr}]"))
+        .collect::<Vec<_>>()
+        .join(",");
+}
+
+/// Set up logging for the binaries. `RUST_LOG` wins if set; otherwise
+/// `verbosity` picks the level: below zero for errors only, zero for
+/// warnings, one for info and two or more for everything.
+pub fn init_logger(verbosity: i32) {
+    let level = match verbosity {
+        i32::MIN..=-1 => log::LevelFilter::Error,
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        _ => log::LevelFilter::Trace,
+    };
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+}
+
+pub fn processed_warcs() -> io::Result<Vec<String>> {
+    FormsDir::default().processed_warcs()
+}
+
+/// Every WARC processed into any of `dirs`, and the directory its summary
+/// lives in. WARCs present in several directories are only included once,
+/// and directories whose index can't be read are skipped.
+pub fn processed_in(dirs: &[FormsDir]) -> Vec<(&FormsDir, String)> {
+    let mut seen = HashSet::new();
+    dirs.iter()
+        .flat_map(|dir| {
+            dir.processed_warcs()
+                .unwrap_or_else(|e| {
+                    warn!(
+                        "Skipping {}: unable to read its index: {}",
+                        dir.path().display(),
+                        e
+                    );
+                    vec![]
+                })
+                .into_iter()
+                .map(move |warc| (dir, warc))
+        })
+        .filter(|(_, warc)| seen.insert(warc.clone()))
+        .collect()
+}
+
+/// Every URL with interesting forms stored in any of `dirs`. Summaries that
+/// can't be read are skipped.
+pub fn all_url_summaries(dirs: &[FormsDir]) -> impl ParallelIterator<Item = URLSummary> + '_ {
+    processed_in(dirs)
+        .into_par_iter()
+        .flat_map(|(dir, warc)| dir.load(&warc))
+        .flat_map(|summary| summary.urls_with_pattern_forms)
+}
+
+/// Every interesting form stored in any of `dirs`, with the attributes its
+/// patterns were read from.
+pub fn all_forms_with_pattern_attrs(
+    dirs: &[FormsDir],
+) -> impl ParallelIterator<Item = (String, Arc<Vec<String>>)> + '_ {
+    processed_in(dirs)
+        .into_par_iter()
+        .flat_map(|(dir, warc)| dir.load(&warc))
+        .flat_map_iter(|summary| {
+            let pattern_attrs = Arc::new(summary.pattern_attrs_or_default());
+            summary
+                .urls_with_pattern_forms
+                .into_iter()
+                .flat_map(|url_summary| url_summary.with_patterns)
+                .map(move |form| (form, pattern_attrs.clone()))
+        })
+}
+
+/// A directory of archive summaries, and the index of the WARCs they
+/// summarise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormsDir(PathBuf);
+
+impl FormsDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FormsDir(path.into())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Make sure the directory exists, with a clear error if something that
+    /// isn't a directory is in the way.
+    pub fn create(&self) -> io::Result<()> {
+        self.check()?;
+        fs::create_dir_all(&self.0)
+    }
+
+    /// Fail if the path exists but isn't a directory.
+    pub fn check(&self) -> io::Result<()> {
+        match fs::metadata(&self.0) {
+            Ok(metadata) if !metadata.is_dir() => Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a directory", self.0.display()),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn index_fn(&self) -> PathBuf {
+        self.0.join("index")
+    }
+
+    /// Downloads pause while this file exists.
+    pub fn pause_fn(&self) -> PathBuf {
+        self.0.join("PAUSE")
+    }
+
+    /// Where `cc-get` reports how its last run went.
+    pub fn run_report_fn(&self) -> PathBuf {
+        self.0.join("run-report.json")
+    }
+
+    pub fn write_run_report(&self, stats: &RunStats) -> io::Result<()> {
+        let report = RunReport {
+            attempted: stats.succeeded + stats.failed,
+            succeeded: stats.succeeded,
+            skipped: stats.skipped,
+            failed: stats.failed,
+            aborted: stats.aborted,
+            failed_warcs: &stats.failed_warcs,
+        };
+        let file = BufWriter::new(fs::File::create(self.run_report_fn())?);
+        serde_json::to_writer_pretty(file, &report)?;
+        Ok(())
+    }
+
+    /// Where a downloaded CommonCrawl resource, e.g. a `warc.paths.gz`, is
+    /// cached between runs.
+    pub fn cache_fn(&self, path: &str) -> PathBuf {
+        self.0.join("cache").join(path.replace('/', "!"))
+    }
+
+    pub fn processed_warcs(&self) -> io::Result<Vec<String>> {
+        Ok(self
+            .index_entries()?
+            .into_iter()
+            .map(|entry| entry.warc_url)
+            .collect())
+    }
+
+    /// Whether the index is missing, empty or ends with a complete line, so
+    /// that it can be appended to as is.
+    fn index_is_terminated(&self) -> bool {
+        let Ok(mut index) = fs::File::open(self.index_fn()) else {
+            return true;
+        };
+        let mut last = [0u8];
+        match index.seek(io::SeekFrom::End(-1)) {
+            Ok(_) => index.read_exact(&mut last).is_ok() && last[0] == b'\n',
+            // Seeking before the start: the index is empty.
+            Err(_) => true,
+        }
+    }
+
+    /// Every entry of the index, with when and how each WARC was processed
+    /// where known. A missing index has no entries; any other error reading
+    /// it is returned, so that it isn't mistaken for an empty one.
+    pub fn index_entries(&self) -> io::Result<Vec<IndexEntry>> {
+        let index = match fs::read(self.index_fn()) {
+            Ok(index) => index,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!(
+                    "No index file found in {}, assuming no previous progress.",
+                    self.0.display()
+                );
+                return Ok(vec![]);
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(String::from_utf8_lossy(&index)
+            .split_inclusive('\n')
+            .filter_map(|line| match line.strip_suffix('\n') {
+                Some(line) => Some(line.trim_end_matches('\r')),
+                None => {
+                    // A crash while appending leaves a partial last line.
+                    warn!("Ignoring unterminated index entry: {}", line);
+                    None
+                }
+            })
+            .filter(|line| !line.is_empty())
+            .map(IndexEntry::parse)
+            .collect())
+    }
+
+    /// Where the summary of a WARC is stored when compressed with `codec`.
+    /// Summaries are sharded into `<crawl>/<segment>/` to keep directories
+    /// reasonably small, falling back to the top level for paths that don't
+    /// look like CommonCrawl WARC paths.
+    pub fn storage_fn(&self, warc_url: &str, codec: Codec) -> PathBuf {
+        match parse_warc_path(warc_url) {
+            Some(info) => self
+                .0
+                .join(info.crawl)
+                .join(info.segment)
+                .join(storage_name(warc_url, codec)),
+            None => self.legacy_storage_fn(warc_url, codec),
+        }
+    }
+
+    /// Where the summary of a WARC was stored before sharding was introduced.
+    pub fn legacy_storage_fn(&self, warc_url: &str, codec: Codec) -> PathBuf {
+        self.0.join(storage_name(warc_url, codec))
+    }
+
+    /// The stored summary for a WARC, in either location and with any codec.
+    /// Newer codecs and the sharded location win if there are several.
+    pub fn find_summary(&self, warc_url: &str) -> Option<PathBuf> {
+        Codec::ALL
+            .iter()
+            .flat_map(|&codec| {
+                [
+                    self.storage_fn(warc_url, codec),
+                    self.legacy_storage_fn(warc_url, codec),
+                ]
+            })
+            .find(|path| path.exists())
+    }
+
+    /// Whether a summary for the WARC is stored, in either location.
+    pub fn has_summary(&self, warc_url: &str) -> bool {
+        self.find_summary(warc_url).is_some()
+    }
+
+    /// Load the stored summary for a WARC, also looking in the pre-sharding
+    /// location and for older codecs.
+    pub fn load(&self, warc_url: &str) -> Result<ArchiveSummary, std::io::Error> {
+        match self.find_summary(warc_url) {
+            Some(path) => ArchiveSummary::from_file(path),
+            None => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("No summary stored for {}", warc_url),
+            )),
+        }
+    }
+}
+
+/// How processing a WARC went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// Only part of the WARC could be read; see `ProcessingOptions::keep_partial`.
+    Partial,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Ok => "ok",
+            Outcome::Partial => "partial",
+        })
+    }
+}
+
+/// A line of the index: a WARC URL, optionally followed by a tab, its
+/// outcome, another tab and when it was processed. Bare URLs, as written by
+/// older versions, are taken to be `ok` at an unknown time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub warc_url: String,
+    pub outcome: Outcome,
+    pub processed_at: Option<DateTime<Utc>>,
+}
+
+impl IndexEntry {
+    fn parse(line: &str) -> IndexEntry {
+        let mut fields = line.split('\t');
+        let warc_url = fields.next().unwrap_or_default().to_owned();
+        let outcome = match fields.next() {
+            None | Some("ok") => Outcome::Ok,
+            Some("partial") => Outcome::Partial,
+            Some(other) => {
+                warn!("Unknown outcome {} for {} in the index", other, warc_url);
+                Outcome::Ok
+            }
+        };
+        let processed_at = fields
+            .next()
+            .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+            .map(|date| date.with_timezone(&Utc));
+        IndexEntry {
+            warc_url,
+            outcome,
+            processed_at,
+        }
+    }
+}
+
+impl fmt::Display for IndexEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\t{}", self.warc_url, self.outcome)?;
+        if let Some(processed_at) = self.processed_at {
+            write!(f, "\t{}", processed_at.to_rfc3339())?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for FormsDir {
+    /// `$FORMS_DIR`, or `forms.d`.
+    fn default() -> Self {
+        FormsDir::new(env::var(FORMS_DIR_VAR).unwrap_or_else(|_| DEFAULT_FORMS_DIR.to_owned()))
+    }
+}
+
+/// The rate limiting state for one host.
+struct HostLimit {
+    /// Offset in milliseconds since started_at of the last request
+    last_req: AtomicU64,
+    wait_time: AtomicU64,
 }
 
-pub fn processed_warcs() -> Vec<String> {
-    match fs::File::open("forms.d/index") {
-        Ok(fp) => BufReader::new(fp).lines().flatten().collect(),
-        Err(_) => {
-            info!("No index file found, assuming no previous progress.");
-            vec![]
+impl HostLimit {
+    fn new() -> Self {
+        HostLimit {
+            last_req: AtomicU64::new(INITIAL_WAIT),
+            wait_time: AtomicU64::new(INITIAL_WAIT),
         }
     }
 }
 
+/// A rate-limited client for CommonCrawl. Clones share their connection pool
+/// and their backoff state, so a server error seen by one clone slows down
+/// all of them. Each host is limited separately, so backing off from one
+/// doesn't hold up requests to another.
 #[derive(Clone)]
 pub struct Client {
     inner: reqwest::blocking::Client,
     started_at: Instant,
-    /// Offset in seconds since started_at of the last request
-    last_req: Arc<AtomicU64>,
-    wait_time: Arc<AtomicU64>,
+    hosts: Arc<Mutex<HashMap<String, Arc<HostLimit>>>>,
+    /// Never wait less than this between requests.
+    min_delay: Duration,
+    base_url: String,
+    index_url: String,
+    pause_file: Option<PathBuf>,
+    /// Never wait or back off; see `ClientOptions::unlimited`.
+    unlimited: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// How many idle connections to keep to the server. Every WARC being
+    /// processed holds one connection for the whole download, so there is no
+    /// point in this being larger than the number of WARCs processed at once.
+    /// Defaults to reqwest's default (no limit).
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Hold off on new requests while this file exists.
+    pub pause_file: Option<PathBuf>,
+    /// Replaces the whole user agent. Falls back to `$BO_CC_USER_AGENT`, and
+    /// then to `bo-cc/<version>`.
+    pub user_agent: Option<String>,
+    /// How to reach whoever is running this, e.g. an email address. Appended
+    /// to the default user agent, as CommonCrawl asks of bulk users.
+    pub contact: Option<String>,
+    /// Where to fetch CommonCrawl data from. Falls back to `$CC_BASE_URL`,
+    /// and then to `https://data.commoncrawl.org/`.
+    pub base_url: Option<String>,
+    /// Where to look up URLs in the CDX index. Falls back to
+    /// `$CC_INDEX_URL`, and then to `https://index.commoncrawl.org/`.
+    pub index_url: Option<String>,
+    /// The least time to leave between requests, even when the server is
+    /// happy. The backoff after server errors comes on top of this.
+    pub min_delay: Duration,
+    /// Turn off rate limiting and backoff entirely, for local mirrors and
+    /// mock servers. Server errors are then returned rather than retried.
+    pub unlimited: bool,
+}
+
+impl ClientOptions {
+    pub fn user_agent(&self) -> String {
+        if let Some(user_agent) = self
+            .user_agent
+            .clone()
+            .or_else(|| env::var(USER_AGENT_VAR).ok())
+        {
+            return user_agent;
+        }
+        let user_agent = format!("bo-cc/{}", env!("CARGO_PKG_VERSION"));
+        match &self.contact {
+            Some(contact) => format!("{user_agent} (+{contact})"),
+            None => user_agent,
+        }
+    }
+
+    pub fn base_url(&self) -> String {
+        self.base_url
+            .clone()
+            .or_else(|| env::var(BASE_URL_VAR).ok())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_owned())
+    }
+
+    pub fn index_url(&self) -> String {
+        self.index_url
+            .clone()
+            .or_else(|| env::var(INDEX_URL_VAR).ok())
+            .unwrap_or_else(|| DEFAULT_INDEX_URL.to_owned())
+    }
 }
 
 impl Client {
     pub fn new() -> Self {
+        Self::with_options(ClientOptions::default())
+    }
+
+    /// A client that never waits between requests; see
+    /// `ClientOptions::unlimited`.
+    pub fn unlimited() -> Self {
+        Self::with_options(ClientOptions {
+            unlimited: true,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_options(options: ClientOptions) -> Self {
+        let mut builder = ClientBuilder::new().user_agent(options.user_agent());
+        if let Some(pool_size) = options.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(pool_size);
+        }
+
         Client {
-            inner: ClientBuilder::new()
-                .user_agent(format!("bo-cc/{}", env!("CARGO_PKG_VERSION")))
-                //.connection_verbose(true)
-                //.default_headers(headers)
-                .build()
-                .unwrap(),
+            inner: builder.build().unwrap(),
             started_at: Instant::now(),
-            last_req: Arc::new(AtomicU64::new(INITIAL_WAIT)),
-            wait_time: Arc::new(AtomicU64::new(INITIAL_WAIT)),
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+            min_delay: if options.unlimited {
+                Duration::ZERO
+            } else {
+                options.min_delay
+            },
+            base_url: options.base_url(),
+            index_url: options.index_url(),
+            pause_file: options.pause_file,
+            unlimited: options.unlimited,
+        }
+    }
+
+    fn wait_while_paused(&self) {
+        let Some(pause_file) = self.pause_file.as_ref() else {
+            return;
+        };
+        if !pause_file.exists() {
+            return;
+        }
+        info!(
+            "Pausing downloads until {} is removed...",
+            pause_file.display()
+        );
+        while pause_file.exists() {
+            thread::sleep(Duration::from_secs(PAUSE_POLL_S));
         }
+        info!("Resuming downloads!");
+    }
+
+    /// The rate limiting state for the host of `url`.
+    fn limit_for(&self, url: &str) -> Arc<HostLimit> {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_owned()))
+            .unwrap_or_default();
+        self.hosts
+            .lock()
+            .expect("Could not get lock on hosts!")
+            .entry(host)
+            .or_insert_with(|| Arc::new(HostLimit::new()))
+            .clone()
+    }
+
+    /// The current backoff, but never less than the minimum delay.
+    fn current_wait(&self, limit: &HostLimit) -> Duration {
+        Duration::from_secs(limit.wait_time.load(Ordering::SeqCst)).max(self.min_delay)
     }
 
-    fn wait_for_our_turn(&mut self) {
-        if self.wait_time.load(Ordering::SeqCst) == 0 {
+    fn wait_for_our_turn(&self, limit: &HostLimit) {
+        if self.current_wait(limit).is_zero() {
             return;
         }
         loop {
-            let seen_last_req = self.last_req.load(Ordering::SeqCst);
-            let offset_to_last_req = self.started_at + Duration::new(seen_last_req, 0);
-            let s_passed = (Instant::now() - offset_to_last_req).as_secs();
-            trace!("Time since last request: {}s", s_passed);
-            if s_passed > self.wait_time.load(Ordering::SeqCst) {
+            let seen_last_req = limit.last_req.load(Ordering::SeqCst);
+            let passed = self
+                .started_at
+                .elapsed()
+                .saturating_sub(Duration::from_millis(seen_last_req));
+            trace!("Time since last request: {:?}", passed);
+            if passed >= self.current_wait(limit) {
                 trace!("Enough time has passed, we get to fetch!");
-                let _ = self.last_req.compare_exchange_weak(
+                let _ = limit.last_req.compare_exchange_weak(
                     seen_last_req,
-                    (Instant::now() - self.started_at).as_secs(),
+                    self.started_at.elapsed().as_millis() as u64,
                     Ordering::SeqCst,
                     Ordering::SeqCst,
                 );
                 break;
             }
+            thread::sleep(Duration::from_millis(WAIT_POLL_MS));
         }
     }
 
     pub fn get(&mut self, path: &str) -> reqwest::Result<Response> {
+        self.get_with_headers(path, HeaderMap::new())
+    }
+
+    pub fn get_with_headers(
+        &mut self,
+        path: &str,
+        headers: HeaderMap,
+    ) -> reqwest::Result<Response> {
+        let url = format!(
+            "{}/{}",
+            self.base_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        self.get_url(&url, headers)
+    }
+
+    /// Like `get_with_headers`, but for a full URL rather than a path below
+    /// the base URL.
+    fn get_url(&mut self, url: &str, headers: HeaderMap) -> reqwest::Result<Response> {
+        let limit = self.limit_for(url);
         loop {
-            self.wait_for_our_turn();
+            self.wait_while_paused();
+            self.wait_for_our_turn(&limit);
 
-            let r = self
-                .inner
-                .get(format!("https://data.commoncrawl.org/{}", path))
-                .send()?;
+            let r = self.inner.get(url).headers(headers.clone()).send()?;
 
             if r.status().is_success() {
-                self.wait_time.store(INITIAL_WAIT, Ordering::SeqCst);
+                limit.wait_time.store(INITIAL_WAIT, Ordering::SeqCst);
                 info!(
                     "Success! Wait time is now: {}s",
-                    self.wait_time.load(Ordering::SeqCst)
+                    limit.wait_time.load(Ordering::SeqCst)
                 );
                 break Ok(r);
             }
 
-            if r.status().is_server_error() {
+            if r.status().is_server_error() && !self.unlimited {
                 info!("Server error: {}. Retrying", r.status());
-                let seen_wait_time = self.wait_time.load(Ordering::SeqCst);
+                let seen_wait_time = limit.wait_time.load(Ordering::SeqCst);
                 if seen_wait_time < MAX_WAIT {
-                    if let Ok(new_time) = self.wait_time.compare_exchange(
+                    if let Ok(new_time) = limit.wait_time.compare_exchange(
                         seen_wait_time,
                         seen_wait_time + 1,
                         Ordering::SeqCst,
@@ -131,6 +657,173 @@ impl Client {
             }
         }
     }
+
+    /// Fetch a (small) resource and read its entire body into memory,
+    /// failing on any non-success status.
+    pub fn get_bytes(&mut self, path: &str) -> reqwest::Result<Vec<u8>> {
+        Ok(self.get(path)?.error_for_status()?.bytes()?.to_vec())
+    }
+
+    /// Like `get_bytes`, but keep a copy in `cache_fn` and only download the
+    /// resource again if the server says it has changed. Problems with the
+    /// cache are logged and otherwise ignored.
+    pub fn get_bytes_cached(&mut self, path: &str, cache_fn: &Path) -> reqwest::Result<Vec<u8>> {
+        let validators_fn = validators_fn(cache_fn);
+        let cached = fs::read(cache_fn).ok().zip(
+            fs::read(&validators_fn)
+                .ok()
+                .and_then(|v| serde_json::from_slice::<CacheValidators>(&v).ok()),
+        );
+
+        let mut headers = HeaderMap::new();
+        if let Some((_, validators)) = &cached {
+            for (name, value) in [
+                (IF_NONE_MATCH, &validators.etag),
+                (IF_MODIFIED_SINCE, &validators.last_modified),
+            ] {
+                if let Some(value) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                    headers.insert(name, value);
+                }
+            }
+        }
+
+        let response = self.get_with_headers(path, headers)?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some((body, _)) = cached {
+                info!("{} not modified, using cached copy", path);
+                return Ok(body);
+            }
+        }
+
+        let response = response.error_for_status()?;
+        let header = |name: HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned())
+        };
+        let validators = CacheValidators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        let body = response.bytes()?.to_vec();
+
+        if let Err(e) = store_cached(cache_fn, &body, &validators) {
+            warn!("Unable to cache {} in {}: {}", path, cache_fn.display(), e);
+        }
+        Ok(body)
+    }
+
+    /// The captures of `url` in `archive`, e.g. `CC-MAIN-2023-40`, according
+    /// to its CDX index. A URL that was never captured has none.
+    pub fn lookup(&mut self, archive: &str, url: &str) -> Result<Vec<CdxEntry>, Box<dyn Error>> {
+        let mut index_url = Url::parse(&format!(
+            "{}/{}-index",
+            self.index_url.trim_end_matches('/'),
+            archive
+        ))?;
+        index_url
+            .query_pairs_mut()
+            .append_pair("url", url)
+            .append_pair("output", "json");
+        let response = self.get_url(index_url.as_str(), HeaderMap::new())?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let body = response.error_for_status()?.text()?;
+        Ok(body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Fetch just the record of a capture, by its byte range in its WARC.
+    pub fn get_record(&mut self, entry: &CdxEntry) -> Result<WarcRecord, Box<dyn Error>> {
+        let offset: u64 = entry.offset.parse()?;
+        let length: u64 = entry.length.parse()?;
+        if length == 0 {
+            return Err(format!("Empty record for {} in {}", entry.url, entry.filename).into());
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            RANGE,
+            HeaderValue::from_str(&format!("bytes={}-{}", offset, offset + length - 1))?,
+        );
+        let response = self
+            .get_with_headers(&entry.filename, headers)?
+            .error_for_status()?;
+        // A server ignoring the range would send us the whole WARC.
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(format!(
+                "Expected a partial response for {} in {}, got {}",
+                entry.url,
+                entry.filename,
+                response.status()
+            )
+            .into());
+        }
+        let failed = Arc::new(AtomicBool::new(false));
+        read_records(response, failed)
+            .next()
+            .ok_or_else(|| format!("No record for {} in {}", entry.url, entry.filename).into())
+    }
+}
+
+/// A capture of a URL, as listed by a CommonCrawl CDX index.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CdxEntry {
+    pub url: String,
+    /// The WARC the capture is stored in.
+    pub filename: String,
+    /// Where the compressed record starts in the WARC, and how long it is.
+    /// The index gives both as strings.
+    pub offset: String,
+    pub length: String,
+}
+
+/// What we need to make a conditional request for a cached resource.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+fn validators_fn(cache_fn: &Path) -> PathBuf {
+    let mut name = cache_fn.as_os_str().to_owned();
+    name.push(".validators.json");
+    PathBuf::from(name)
+}
+
+fn store_cached(cache_fn: &Path, body: &[u8], validators: &CacheValidators) -> io::Result<()> {
+    if let Some(parent) = cache_fn.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(cache_fn, body)?;
+    fs::write(validators_fn(cache_fn), serde_json::to_vec(validators)?)
+}
+
+/// Sync `dir` to disk, so that files created in or moved into it stay there.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriterOptions {
+    pub dir: FormsDir,
+    /// Make sure each archive is on disk before it is added to the index, and
+    /// that the index is on disk before moving on. Costs throughput.
+    pub durable: bool,
+    /// Pretty-print the stored JSON, for reading it by hand. Makes the
+    /// archives bigger.
+    pub pretty: bool,
+    /// How to compress the stored summaries.
+    pub codec: Codec,
+    /// Append to the existing index instead of rewriting it at startup. This
+    /// skips dropping entries whose summaries are missing; the index is
+    /// still rewritten if its last line is unterminated.
+    pub skip_index_rewrite: bool,
 }
 
 pub struct AnalysisWriter {
@@ -139,31 +832,123 @@ pub struct AnalysisWriter {
 }
 
 impl AnalysisWriter {
-    fn process_inbox(incoming: Receiver<UrlAndSummary>) {
+    fn process_inbox(incoming: Receiver<UrlAndSummary>, options: WriterOptions) {
         info!("Writer thread started!");
-        fs::create_dir_all("forms.d").expect("Unable to create forms.d directory!");
-        let seen = processed_warcs();
-        let mut index_bw =
-            BufWriter::new(fs::File::create("forms.d/index").expect("Unable to open index file"));
-        for s in seen.into_iter() {
-            writeln!(index_bw, "{}", s).expect("Unable to rewrite index!");
+        if let Err(e) = options.dir.create() {
+            panic!("Unable to create output directory: {}", e);
         }
-        index_bw.flush().expect("Unable to write to index!");
-
-        while let Ok((warc_url, summary)) = incoming.recv() {
-            let archive_fn = to_storage_fn(&warc_url);
-            let archive_writer = XzEncoder::new(
-                BufWriter::new(fs::File::create(&archive_fn).unwrap_or_else(|_| {
-                    panic!("Unable to open archive dump file: {}", &archive_fn)
-                })),
-                COMPRESSION_LEVEL,
+        let append = options.skip_index_rewrite && options.dir.index_is_terminated();
+        if options.skip_index_rewrite && !append {
+            warn!("The index ends with a partial line, so rewriting it anyway");
+        }
+        let mut index_bw = if append {
+            let index_bw = BufWriter::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(options.dir.index_fn())
+                    .expect("Unable to open index file"),
             );
+            if options.durable {
+                sync_dir(options.dir.path()).expect("Unable to sync output directory!");
+            }
+            index_bw
+        } else {
+            // Read the old index before replacing it, and give up rather
+            // than lose it if that fails.
+            let seen = options
+                .dir
+                .index_entries()
+                .unwrap_or_else(|e| panic!("Unable to read index file: {}", e));
+            // Write the new index next to the old one and move it into place,
+            // so a crash half way leaves one or the other.
+            let new_index_fn = options.dir.path().join("index.new");
+            let mut index_bw =
+                BufWriter::new(fs::File::create(&new_index_fn).expect("Unable to open index file"));
+            // Drop entries whose summaries have gone missing, so the index only
+            // lists WARCs we actually have results for.
+            for entry in seen.into_iter() {
+                if !options.dir.has_summary(&entry.warc_url) {
+                    warn!(
+                        "Dropping {} from the index: its summary is missing",
+                        entry.warc_url
+                    );
+                    continue;
+                }
+                writeln!(index_bw, "{}", entry).expect("Unable to rewrite index!");
+            }
+            index_bw.flush().expect("Unable to write to index!");
+            if options.durable {
+                index_bw
+                    .get_ref()
+                    .sync_all()
+                    .expect("Unable to sync index to disk!");
+            }
+            // The open file follows the rename, so we keep appending to it.
+            fs::rename(new_index_fn, options.dir.index_fn()).expect("Unable to replace index!");
+            if options.durable {
+                sync_dir(options.dir.path()).expect("Unable to sync output directory!");
+            }
+            index_bw
+        };
 
-            serde_json::to_writer(archive_writer, &summary)
+        while let Ok((warc_url, summary)) = incoming.recv() {
+            let archive_fn = options.dir.storage_fn(&warc_url, options.codec);
+            let archive_dir = archive_fn.parent().unwrap_or(options.dir.path());
+            if !archive_dir.exists() {
+                fs::create_dir_all(archive_dir).expect("Unable to create shard directory!");
+                if options.durable {
+                    // Make the new shard directories themselves stick.
+                    for dir in archive_dir.ancestors().skip(1) {
+                        sync_dir(dir).expect("Unable to sync shard directory!");
+                        if dir == options.dir.path() {
+                            break;
+                        }
+                    }
+                }
+            }
+            let archive_file = options
+                .codec
+                .encode(
+                    BufWriter::new(fs::File::create(&archive_fn).unwrap_or_else(|_| {
+                        panic!("Unable to open archive dump file: {}", archive_fn.display())
+                    })),
+                    |archive_writer| {
+                        if options.pretty {
+                            serde_json::to_writer_pretty(archive_writer, &summary)
+                        } else {
+                            serde_json::to_writer(archive_writer, &summary)
+                        }
+                        .map_err(io::Error::from)
+                    },
+                )
+                .expect("Error compressing archive summary!")
+                .into_inner()
                 .expect("Error writing archive summary!");
+            if options.durable {
+                archive_file
+                    .sync_all()
+                    .expect("Unable to sync archive summary to disk!");
+                sync_dir(archive_dir).expect("Unable to sync shard directory!");
+            }
 
-            writeln!(index_bw, "{}", warc_url).expect("Unable to write WARC URL to index!");
+            let entry = IndexEntry {
+                outcome: if summary.nr_partial_warcs > 0 {
+                    Outcome::Partial
+                } else {
+                    Outcome::Ok
+                },
+                warc_url,
+                processed_at: Some(SystemTime::now().into()),
+            };
+            writeln!(index_bw, "{}", entry).expect("Unable to write WARC URL to index!");
             index_bw.flush().expect("Unable to write to index!");
+            if options.durable {
+                index_bw
+                    .get_ref()
+                    .sync_data()
+                    .expect("Unable to sync index to disk!");
+            }
         }
     }
     pub fn write(
@@ -177,16 +962,89 @@ impl AnalysisWriter {
         Ok(())
     }
     pub fn new() -> Self {
+        Self::with_options(WriterOptions::default())
+    }
+    pub fn with_options(options: WriterOptions) -> Self {
         let (send, recieve) = std::sync::mpsc::sync_channel(WRITE_BACKLOG);
         Self {
             inbox: Some(send),
-            thread: Some(thread::spawn(move || Self::process_inbox(recieve))),
+            thread: Some(thread::spawn(move || Self::process_inbox(recieve, options))),
         }
     }
 }
 
+fn storage_name(warc_url: &str, codec: Codec) -> String {
+    format!("{}{}", warc_url.replace('/', "!"), codec.extension())
+}
+
+/// Where the summary of a WARC is stored in the default output directory.
 pub fn to_storage_fn(warc_url: &str) -> String {
-    format!("forms.d/{}.json.xz", warc_url.replace('/', "!"))
+    FormsDir::default()
+        .storage_fn(warc_url, Codec::default())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The WARC URL a stored summary belongs to, from its file name. Works for
+/// both sharded and legacy paths, as the file name is the same in both, and
+/// for every codec.
+pub fn from_storage_fn(file_name: impl AsRef<Path>) -> Option<String> {
+    let name = file_name.as_ref().file_name()?.to_str()?;
+    Codec::ALL
+        .iter()
+        .find_map(|codec| name.strip_suffix(codec.extension()))
+        .map(|warc| warc.replace('!', "/"))
+}
+
+/// The host part of a captured page's URL.
+pub fn host_of(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(|host| host.to_owned())
+}
+
+/// The last label of a captured page's host, e.g. `jp`.
+fn tld_of(url: &str) -> Option<String> {
+    let host = host_of(url)?;
+    host.rsplit('.').next().map(|tld| tld.to_owned())
+}
+
+/// The public suffix (e.g. `com` or `co.uk`) of a captured page's URL,
+/// according to the public suffix list.
+pub fn public_suffix(url: &str) -> Option<String> {
+    let host = host_of(url)?;
+    psl::suffix_str(&host).map(|suffix| suffix.to_owned())
+}
+
+/// The parts of a WARC path, e.g.
+/// `crawl-data/CC-MAIN-2023-40/segments/1695233505362.29/warc/CC-MAIN-[...].warc.gz`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WarcPathInfo {
+    pub crawl: String,
+    pub segment: String,
+    pub filename: String,
+}
+
+pub fn parse_warc_path(warc_url: &str) -> Option<WarcPathInfo> {
+    let mut parts = warc_url.trim_start_matches('/').split('/');
+    if parts.next()? != "crawl-data" {
+        return None;
+    }
+    let crawl = parts.next()?;
+    if parts.next()? != "segments" {
+        return None;
+    }
+    let segment = parts.next()?;
+    parts.next()?; // The kind of file, e.g. warc, wat, or wet
+    let filename = parts.next()?;
+
+    if parts.next().is_some() || [crawl, segment, filename].iter().any(|p| p.is_empty()) {
+        return None;
+    }
+
+    Some(WarcPathInfo {
+        crawl: crawl.to_owned(),
+        segment: segment.to_owned(),
+        filename: filename.to_owned(),
+    })
 }
 
 impl Drop for AnalysisWriter {
@@ -208,6 +1066,39 @@ impl Default for AnalysisWriter {
 pub struct URLSummary {
     pub url: String,
     pub with_patterns: Vec<String>,
+    /// The page's declared language, from `<html lang>` or the
+    /// `Content-Language` header.
+    #[serde(default)]
+    pub language: Option<String>,
+    /// The HTTP status code the page was captured with.
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// What we know about each form in `with_patterns`, in the same order.
+    /// Empty for summaries written before this was recorded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub form_details: Vec<FormDetails>,
+    /// Patterns across the page's interesting forms, counting repeats, and
+    /// without. Both are zero in summaries written before they were recorded.
+    #[serde(default)]
+    pub nr_patterns: usize,
+    #[serde(default)]
+    pub nr_distinct_patterns: usize,
+}
+
+/// Facts about an interesting form, gathered while extracting it.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FormDetails {
+    /// Inputs in the form with at least one recognised pattern attribute.
+    pub nr_patterned_inputs: usize,
+    /// The size of the form's (decoded) HTML.
+    #[serde(default)]
+    pub nr_bytes: usize,
+    /// The form's `id`, which often says what it is for, e.g. `login-form`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The form's `class`, as written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -216,17 +1107,206 @@ pub struct ArchiveSummary {
     pub nr_urls_without_patterns: i64,
     pub nr_forms_without_patterns: i64,
     pub urls_with_pattern_forms: Vec<URLSummary>,
+    /// Records that were skipped before decoding because they did not look
+    /// like HTML.
+    #[serde(default)]
+    pub nr_skipped_non_html: i64,
+    /// Records whose HTTP headers could not be fully parsed, so the entire
+    /// response was treated as the body. These are still counted in the
+    /// other fields, but their results may be off.
+    #[serde(default)]
+    pub nr_header_parse_fallback: i64,
+    /// Records skipped because their capture date was outside the requested
+    /// window.
+    #[serde(default)]
+    pub nr_outside_date_range: i64,
+    /// Every record read from the WARC, whatever its type. An unusually low
+    /// number suggests a truncated download.
+    #[serde(default)]
+    pub nr_records_seen: i64,
+    /// HTML responses skipped because their HTTP status was not 2xx.
+    #[serde(default)]
+    pub nr_non_success: i64,
+    /// Interesting forms skipped because only their opening tag could be
+    /// extracted. The rest of their page is still processed.
+    #[serde(default)]
+    pub nr_truncated_forms: i64,
+    /// With `ProcessingOptions::record_errors`, the first few records we
+    /// couldn't extract forms from, and why.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub record_errors: Vec<RecordError>,
+    /// WARCs that had plenty of records but not a single HTML page, either
+    /// because they really are all media or because something went wrong.
+    #[serde(default)]
+    pub nr_warcs_without_html: i64,
+    /// Pages longer than `ProcessingOptions::max_body_bytes`, of which only
+    /// the beginning was searched for forms.
+    #[serde(default)]
+    pub nr_truncated_bodies: i64,
+    /// Responses left out by `ProcessingOptions::sample`. The other counts
+    /// only cover the responses that were kept.
+    #[serde(default)]
+    pub nr_sampled_out: i64,
+    /// WARCs that could only be read part-way, kept because of
+    /// `ProcessingOptions::keep_partial`.
+    #[serde(default)]
+    pub nr_partial_warcs: i64,
+    /// Pages with `<iframe>`s, whose forms we can't see as they are in other
+    /// records.
+    #[serde(default)]
+    pub nr_urls_with_iframes: i64,
+    /// Pages with `<iframe>`s but no forms of their own: possibly pages
+    /// with forms we can't see, rather than without forms.
+    #[serde(default)]
+    pub nr_urls_with_only_iframes: i64,
+    /// Interesting forms left out by
+    /// `ProcessingOptions::collapse_duplicate_forms`.
+    #[serde(default)]
+    pub nr_collapsed_forms: i64,
+    /// AMP documents, and forms either in them or using amp-form
+    /// attributes, with `ProcessingOptions::detect_amp`. AMP forms are also
+    /// counted with the other forms.
+    #[serde(default)]
+    pub nr_amp_pages: i64,
+    #[serde(default)]
+    pub nr_amp_forms: i64,
+    /// How many pages were decoded with each encoding, by name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub encodings: HashMap<String, u64>,
+    /// The attributes patterns were read from, if not `INTERESTING_ATTRS`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pattern_attrs: Vec<String>,
+    /// Pages with invalid bytes that were decoded anyway, with
+    /// `ProcessingOptions::lossy_decode`. Without it, they count as of
+    /// unknown encoding.
+    #[serde(default)]
+    pub nr_lossy_decode: i64,
+}
+
+/// Why forms couldn't be extracted from a page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordError {
+    pub url: String,
+    pub error: String,
+}
+
+/// The compression of a stored summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    Xz,
+    Gzip,
+    #[default]
+    Zstd,
+}
+
+impl Codec {
+    /// Every codec, newest first.
+    pub const ALL: [Codec; 3] = [Codec::Zstd, Codec::Xz, Codec::Gzip];
+
+    /// The file name suffix of summaries compressed with this codec.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Xz => ".json.xz",
+            Codec::Gzip => ".json.gz",
+            Codec::Zstd => ".json.zst",
+        }
+    }
+
+    /// Recognise the codec of a file from its first few bytes.
+    pub fn sniff(magic: &[u8]) -> Option<Codec> {
+        if magic.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Codec::Xz)
+        } else if magic.starts_with(&[0x1F, 0x8B]) {
+            Some(Codec::Gzip)
+        } else if magic.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Some(Codec::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Codec::Xz => Box::new(XzDecoder::new(reader)),
+            Codec::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Codec::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        })
+    }
+
+    /// Compress everything `write` writes into `inner`, and hand `inner`
+    /// back once the stream is finished.
+    fn encode<W: Write>(
+        self,
+        inner: W,
+        write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<W> {
+        match self {
+            Codec::Xz => {
+                let mut encoder = XzEncoder::new(inner, XZ_LEVEL);
+                write(&mut encoder)?;
+                encoder.finish()
+            }
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(inner, flate2::Compression::default());
+                write(&mut encoder)?;
+                encoder.finish()
+            }
+            Codec::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(inner, ZSTD_LEVEL)?;
+                write(&mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
 }
 
 impl ArchiveSummary {
-    pub fn from_file(file_name: &str) -> Result<Self, std::io::Error> {
-        let x: ArchiveSummary =
-            serde_json::from_reader(BufReader::new(XzDecoder::new(fs::File::open(file_name)?)))?;
-        Ok(x)
+    /// Read a stored summary, whichever codec it was compressed with. Errors
+    /// keep their kind, but say which file, and which step, failed.
+    pub fn from_file(file_name: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        let file_name = file_name.as_ref();
+        let context = |what: &str, e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("{}: {} failed: {}", file_name.display(), what, e),
+            )
+        };
+
+        let mut reader =
+            BufReader::new(fs::File::open(file_name).map_err(|e| context("opening", e))?);
+        let codec = Codec::sniff(reader.fill_buf().map_err(|e| context("reading", e))?)
+            .ok_or_else(|| {
+                context(
+                    "decompression",
+                    io::Error::new(ErrorKind::InvalidData, "Unknown compression format"),
+                )
+            })?;
+        let decoder = codec
+            .decoder(reader)
+            .map_err(|e| context("decompression", e))?;
+        serde_json::from_reader(BufReader::new(decoder)).map_err(|e| {
+            if e.is_io() {
+                context("decompression", e.into())
+            } else {
+                context("JSON parsing", e.into())
+            }
+        })
+    }
+
+    /// Load the stored summary for a WARC from the default output directory.
+    pub fn load(warc_url: &str) -> Result<Self, std::io::Error> {
+        FormsDir::default().load(warc_url)
     }
     pub fn merge(self, other: ArchiveSummary) -> ArchiveSummary {
         let mut summarised_forms = self.urls_with_pattern_forms;
         summarised_forms.extend(other.urls_with_pattern_forms);
+        let mut record_errors = self.record_errors;
+        record_errors.extend(other.record_errors);
+        record_errors.truncate(MAX_RECORD_ERRORS);
+        let mut encodings = self.encodings;
+        for (encoding, count) in other.encodings {
+            *encodings.entry(encoding).or_insert(0) += count;
+        }
         ArchiveSummary {
             nr_unknown_encoding: self.nr_unknown_encoding + other.nr_unknown_encoding,
             nr_urls_without_patterns: self.nr_urls_without_patterns
@@ -234,81 +1314,509 @@ impl ArchiveSummary {
             nr_forms_without_patterns: self.nr_forms_without_patterns
                 + other.nr_forms_without_patterns,
             urls_with_pattern_forms: summarised_forms,
+            nr_skipped_non_html: self.nr_skipped_non_html + other.nr_skipped_non_html,
+            nr_header_parse_fallback: self.nr_header_parse_fallback
+                + other.nr_header_parse_fallback,
+            nr_outside_date_range: self.nr_outside_date_range + other.nr_outside_date_range,
+            nr_records_seen: self.nr_records_seen + other.nr_records_seen,
+            nr_non_success: self.nr_non_success + other.nr_non_success,
+            nr_truncated_forms: self.nr_truncated_forms + other.nr_truncated_forms,
+            record_errors,
+            nr_warcs_without_html: self.nr_warcs_without_html + other.nr_warcs_without_html,
+            nr_truncated_bodies: self.nr_truncated_bodies + other.nr_truncated_bodies,
+            nr_sampled_out: self.nr_sampled_out + other.nr_sampled_out,
+            nr_partial_warcs: self.nr_partial_warcs + other.nr_partial_warcs,
+            nr_urls_with_iframes: self.nr_urls_with_iframes + other.nr_urls_with_iframes,
+            nr_urls_with_only_iframes: self.nr_urls_with_only_iframes
+                + other.nr_urls_with_only_iframes,
+            nr_collapsed_forms: self.nr_collapsed_forms + other.nr_collapsed_forms,
+            nr_amp_pages: self.nr_amp_pages + other.nr_amp_pages,
+            nr_amp_forms: self.nr_amp_forms + other.nr_amp_forms,
+            encodings,
+            nr_lossy_decode: self.nr_lossy_decode + other.nr_lossy_decode,
+            pattern_attrs: if self.pattern_attrs.is_empty() {
+                other.pattern_attrs
+            } else {
+                self.pattern_attrs
+            },
         }
     }
 
-    fn from_record(record: rust_warc::WarcRecord) -> Option<ArchiveSummary> {
-        let content_type = record.header.get(&"warc-identified-payload-type".into())?;
-
-        if !(content_type == "text/html" || content_type == "application/xhtml+xml") {
-            trace!("Ignoring unknown content type: {}", content_type);
-            return None;
+    /// The attributes the summary's patterns were read from.
+    pub fn pattern_attrs_or_default(&self) -> Vec<String> {
+        if self.pattern_attrs.is_empty() {
+            default_pattern_attrs()
+        } else {
+            self.pattern_attrs.clone()
         }
+    }
 
-        let (nr_forms, with) = match extract_forms(&record.content) {
-            Ok(res) => res,
-            Err(e) => {
-                trace!(
-                    "Unable to extract forms for URL {}: {}",
-                    record.header.get(&"warc-target-uri".into())?,
-                    e
-                );
-                return Some(ArchiveSummary {
-                    nr_unknown_encoding: 1,
-                    ..Default::default()
-                });
-            }
-        };
+    /// Responses that were handled as HTML pages, whatever came of them.
+    pub fn nr_html_records(&self) -> i64 {
+        self.nr_unknown_encoding
+            + self.nr_urls_without_patterns
+            + self.urls_with_pattern_forms.len() as i64
+            + self.nr_non_success
+    }
 
-        if nr_forms == 0 || with.is_empty() {
-            return Some(ArchiveSummary {
-                nr_urls_without_patterns: 1,
+    /// Pages that were decoded, with or without interesting forms.
+    pub fn successful_urls(&self) -> i64 {
+        self.urls_with_pattern_forms.len() as i64 + self.nr_urls_without_patterns
+    }
+
+    /// Pages that forms were looked for in, whether that worked or not.
+    pub fn total_urls(&self) -> i64 {
+        self.successful_urls() + self.nr_unknown_encoding
+    }
+
+    /// Forms with patterns, including any collapsed away.
+    pub fn forms_with_patterns(&self) -> i64 {
+        self.urls_with_pattern_forms
+            .iter()
+            .map(|u| u.with_patterns.len() as i64)
+            .sum::<i64>()
+            + self.nr_collapsed_forms
+    }
+
+    pub fn total_forms(&self) -> i64 {
+        self.forms_with_patterns() + self.nr_forms_without_patterns
+    }
+
+    /// Whether nothing at all was found or counted, e.g. for a WARC that
+    /// couldn't be read.
+    pub fn is_empty(&self) -> bool {
+        self.urls_with_pattern_forms.is_empty()
+            && self.record_errors.is_empty()
+            && self.encodings.is_empty()
+            && [
+                self.nr_unknown_encoding,
+                self.nr_urls_without_patterns,
+                self.nr_forms_without_patterns,
+                self.nr_skipped_non_html,
+                self.nr_header_parse_fallback,
+                self.nr_outside_date_range,
+                self.nr_records_seen,
+                self.nr_non_success,
+                self.nr_truncated_forms,
+                self.nr_warcs_without_html,
+                self.nr_truncated_bodies,
+                self.nr_sampled_out,
+                self.nr_partial_warcs,
+                self.nr_urls_with_iframes,
+                self.nr_urls_with_only_iframes,
+                self.nr_collapsed_forms,
+                self.nr_amp_pages,
+                self.nr_amp_forms,
+                self.nr_lossy_decode,
+            ]
+            .iter()
+            .all(|&count| count == 0)
+    }
+
+    fn from_record(record: WarcRecord, options: &ProcessingOptions) -> Option<ArchiveSummary> {
+        // Records without an identified payload type are sniffed like any
+        // other payload, and counted as skipped if they don't look like HTML.
+        if let Some(content_type) = record.header.get(&"warc-identified-payload-type".into()) {
+            if !(content_type == "text/html" || content_type == "application/xhtml+xml") {
+                trace!("Ignoring unknown content type: {}", content_type);
+                return None;
+            }
+        }
+
+        let payload = HttpPayload::parse(&record.content);
+        let header_parse_fallback = matches!(
+            payload,
+            Ok(HttpPayload {
+                headers_complete: false,
+                ..
+            })
+        );
+        let status = payload.as_ref().ok().and_then(|payload| payload.status);
+        // Only pages that would otherwise be searched for forms count as
+        // non-2xx; anything else is skipped as non-HTML, whatever its status.
+        let non_success = !status.map_or(true, |code| (200..300).contains(&code));
+        if options.only_success
+            && non_success
+            && payload
+                .as_ref()
+                .map_or(false, |payload| payload.looks_like_html())
+        {
+            trace!("Skipping non-2xx ({:?}) response", status);
+            return Some(ArchiveSummary {
+                nr_non_success: 1,
                 ..Default::default()
             });
         }
+        let tld = if options.tld_hint {
+            record
+                .header
+                .get(&"warc-target-uri".into())
+                .and_then(|url| tld_of(url))
+        } else {
+            None
+        };
+        let summary = Self::from_payload(&record, payload, status, tld, options)?;
+
+        Some(ArchiveSummary {
+            nr_header_parse_fallback: header_parse_fallback as i64,
+            ..summary
+        })
+    }
 
-        let url = {
-            let mut header = record.header;
-            header.remove(&"warc-target-uri".into())?
+    fn from_payload(
+        record: &WarcRecord,
+        payload: Result<HttpPayload, httparse::Error>,
+        status: Option<u16>,
+        tld: Option<String>,
+        options: &ProcessingOptions,
+    ) -> Option<ArchiveSummary> {
+        let forms = payload.map_err(Box::<dyn Error>::from).and_then(|payload| {
+            if payload.looks_like_html() {
+                extract_forms(&payload, tld.as_deref(), options).map(Some)
+            } else {
+                Ok(None)
+            }
+        });
+
+        let PageForms {
+            nr_forms,
+            interesting: with,
+            details: form_details,
+            language,
+            nr_truncated_forms,
+            body_truncated,
+            nr_iframes,
+            nr_patterns,
+            nr_distinct_patterns,
+            nr_collapsed_forms,
+            is_amp,
+            nr_amp_forms,
+            encoding,
+            lossy_decode,
+        } = match forms {
+            Ok(Some(res)) => res,
+            Ok(None) => {
+                trace!(
+                    "Skipping non-HTML payload for URL {}",
+                    record.header.get(&"warc-target-uri".into())?
+                );
+                return Some(ArchiveSummary {
+                    nr_skipped_non_html: 1,
+                    ..Default::default()
+                });
+            }
+            Err(e) => {
+                let url = record.header.get(&"warc-target-uri".into())?;
+                trace!("Unable to extract forms for URL {}: {}", url, e);
+                let record_errors = if options.record_errors {
+                    vec![RecordError {
+                        url: url.to_owned(),
+                        error: e.to_string(),
+                    }]
+                } else {
+                    Vec::new()
+                };
+                return Some(ArchiveSummary {
+                    nr_unknown_encoding: 1,
+                    record_errors,
+                    ..Default::default()
+                });
+            }
         };
 
+        let nr_urls_with_iframes = (nr_iframes > 0) as i64;
+        let nr_amp_pages = is_amp as i64;
+        let encodings = HashMap::from([(encoding.to_owned(), 1)]);
+        let nr_lossy_decode = lossy_decode as i64;
+
+        if nr_forms == 0 || with.is_empty() {
+            return Some(ArchiveSummary {
+                nr_urls_without_patterns: 1,
+                nr_truncated_forms,
+                nr_truncated_bodies: body_truncated as i64,
+                nr_urls_with_iframes,
+                nr_urls_with_only_iframes: (nr_forms == 0 && nr_iframes > 0) as i64,
+                nr_collapsed_forms,
+                nr_amp_pages,
+                nr_amp_forms,
+                encodings,
+                nr_lossy_decode,
+                ..Default::default()
+            });
+        }
+
+        let url = record.header.get(&"warc-target-uri".into())?.to_owned();
+
         Some(ArchiveSummary {
-            nr_forms_without_patterns: nr_forms - with.len() as i64,
+            nr_forms_without_patterns: nr_forms
+                - nr_truncated_forms
+                - nr_collapsed_forms
+                - with.len() as i64,
+            nr_collapsed_forms,
+            nr_amp_pages,
+            nr_amp_forms,
+            encodings,
+            nr_lossy_decode,
+            nr_truncated_forms,
+            nr_truncated_bodies: body_truncated as i64,
+            nr_urls_with_iframes,
             urls_with_pattern_forms: vec![URLSummary {
                 url,
                 with_patterns: with,
+                language,
+                status,
+                form_details,
+                nr_patterns,
+                nr_distinct_patterns,
             }],
             ..Default::default()
         })
     }
 }
 
-fn get_encoding_by_header(headers: [Header; 64]) -> Option<&'static Encoding> {
-    headers
-        .into_iter()
-        .take_while(|h| h != &httparse::EMPTY_HEADER)
-        .find(|h| h.name == "Content-type")
-        .map(|h| String::from_utf8_lossy(h.value))
-        .and_then(|content_type| {
-            let mut iter = content_type.split("; charset=");
-            iter.next(); // Skip first half
-            iter.next().map(|s| s.to_owned())
+/// Aggregate statistics over a set of archive summaries.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub urls_with_pattern: i64,
+    pub total_urls: i64,
+    pub forms_with_pattern: i64,
+    pub total_forms: i64,
+    pub nr_unknown_encoding: i64,
+    pub successful_urls: i64,
+    pub nr_header_parse_fallback: i64,
+    pub nr_warcs_without_html: i64,
+    /// Patterns across all forms with patterns. Forms that can't be parsed
+    /// count as having none.
+    pub nr_patterns: i64,
+    pub nr_urls_with_iframes: i64,
+    pub nr_urls_with_only_iframes: i64,
+}
+
+impl Stats {
+    pub fn from_summary(summary: &ArchiveSummary) -> Stats {
+        let pattern_attrs = summary.pattern_attrs_or_default();
+        // Pages are only stored with at least one pattern, so a zero count
+        // means the summary is from before it was recorded: count them again.
+        let nr_patterns: i64 = summary
+            .urls_with_pattern_forms
+            .iter()
+            .map(|u| match u.nr_patterns {
+                0 => u
+                    .with_patterns
+                    .iter()
+                    .map(|form| {
+                        patterns_in_with(form, &pattern_attrs)
+                            .map_or(0, |patterns| patterns.len() as i64)
+                    })
+                    .sum(),
+                recorded => recorded as i64,
+            })
+            .sum();
+
+        Stats {
+            urls_with_pattern: summary.urls_with_pattern_forms.len() as i64,
+            total_urls: summary.total_urls(),
+            forms_with_pattern: summary.forms_with_patterns(),
+            total_forms: summary.total_forms(),
+            nr_unknown_encoding: summary.nr_unknown_encoding,
+            successful_urls: summary.successful_urls(),
+            nr_header_parse_fallback: summary.nr_header_parse_fallback,
+            nr_warcs_without_html: summary.nr_warcs_without_html,
+            nr_patterns,
+            nr_urls_with_iframes: summary.nr_urls_with_iframes,
+            nr_urls_with_only_iframes: summary.nr_urls_with_only_iframes,
+        }
+    }
+
+    pub fn merge(self, other: Stats) -> Stats {
+        Stats {
+            urls_with_pattern: self.urls_with_pattern + other.urls_with_pattern,
+            total_urls: self.total_urls + other.total_urls,
+            forms_with_pattern: self.forms_with_pattern + other.forms_with_pattern,
+            total_forms: self.total_forms + other.total_forms,
+            nr_unknown_encoding: self.nr_unknown_encoding + other.nr_unknown_encoding,
+            successful_urls: self.successful_urls + other.successful_urls,
+            nr_header_parse_fallback: self.nr_header_parse_fallback
+                + other.nr_header_parse_fallback,
+            nr_warcs_without_html: self.nr_warcs_without_html + other.nr_warcs_without_html,
+            nr_patterns: self.nr_patterns + other.nr_patterns,
+            nr_urls_with_iframes: self.nr_urls_with_iframes + other.nr_urls_with_iframes,
+            nr_urls_with_only_iframes: self.nr_urls_with_only_iframes
+                + other.nr_urls_with_only_iframes,
+        }
+    }
+
+    pub fn unknown_encoding_percent(&self) -> f64 {
+        100f64 * (self.nr_unknown_encoding as f64 / self.total_urls as f64)
+    }
+
+    pub fn urls_with_pattern_percent(&self) -> f64 {
+        100f64 * (self.urls_with_pattern as f64 / self.total_urls as f64)
+    }
+
+    pub fn forms_with_pattern_percent(&self) -> f64 {
+        100f64 * (self.forms_with_pattern as f64 / self.total_forms as f64)
+    }
+
+    /// The average number of patterns in a form with patterns.
+    pub fn patterns_per_form(&self) -> f64 {
+        self.nr_patterns as f64 / self.forms_with_pattern as f64
+    }
+}
+
+/// Tally up `summaries`, e.g. all the stored summaries of a crawl.
+pub fn summarize(summaries: impl ParallelIterator<Item = ArchiveSummary>) -> Stats {
+    summaries
+        .map(|summary| Stats::from_summary(&summary))
+        .reduce(Stats::default, Stats::merge)
+}
+
+fn get_encoding_by_header(payload: &HttpPayload) -> Option<&'static Encoding> {
+    payload
+        .header("Content-Type")
+        .and_then(|content_type| charset_of(&content_type).and_then(Encoding::for_label))
+}
+
+/// The `charset` parameter of a Content-Type value, if any. Parameters may
+/// come in any order, with or without whitespace or quotes, in any case.
+fn charset_of(content_type: &str) -> Option<&[u8]> {
+    content_type
+        .split(';')
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches(|c| c == '"' || c == '\''))
+        .filter(|charset| !charset.is_empty())
+        .map(str::as_bytes)
+}
+
+struct DecodedBody<'a> {
+    text: Cow<'a, str>,
+    content_language: Option<String>,
+    /// Only a prefix of the body was decoded.
+    truncated: bool,
+    encoding: &'static Encoding,
+    /// Some bytes were invalid and replaced.
+    lossy: bool,
+}
+
+/// The HTTP response stored in a WARC response record.
+struct HttpPayload<'a> {
+    headers: [Header<'a>; 64],
+    body: &'a [u8],
+    /// False if the headers could not be parsed in full, and `body` is the
+    /// entire response.
+    headers_complete: bool,
+    status: Option<u16>,
+}
+
+impl<'a> HttpPayload<'a> {
+    fn parse(content: &'a [u8]) -> Result<Self, httparse::Error> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+
+        let (body, headers_complete) =
+            if let httparse::Status::Complete(body_offset) = response.parse(content)? {
+                (&content[body_offset..], true)
+            } else {
+                warn!("Unable to parse headers, using entire request as body!");
+                // Fall back to using the entire response: this is wrong, but probably OK
+                (content, false)
+            };
+
+        let status = response.code;
+
+        Ok(HttpPayload {
+            headers,
+            body,
+            headers_complete,
+            status,
         })
-        .and_then(|content_type| Encoding::for_label(content_type.as_bytes()))
+    }
+
+    fn header(&self, name: &str) -> Option<Cow<'a, str>> {
+        self.headers
+            .iter()
+            .take_while(|h| h != &&httparse::EMPTY_HEADER)
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| String::from_utf8_lossy(h.value))
+    }
+
+    fn is_xhtml(&self) -> bool {
+        self.header("Content-Type").map_or(false, |content_type| {
+            content_type
+                .to_ascii_lowercase()
+                .contains("application/xhtml+xml")
+        })
+    }
+
+    /// A cheap check that the payload is worth decoding and parsing as HTML:
+    /// either the server said so, or it starts like an HTML document.
+    fn looks_like_html(&self) -> bool {
+        if let Some(content_type) = self.header("Content-Type") {
+            if content_type.to_ascii_lowercase().contains("html") {
+                return true;
+            }
+        }
+
+        const SNIFF_BYTES: usize = 1024;
+        let prefix = self.body[..self.body.len().min(SNIFF_BYTES)].to_ascii_lowercase();
+        [
+            b"<html".as_slice(),
+            b"<!doctype",
+            b"<head",
+            b"<body",
+            b"<form",
+        ]
+        .iter()
+        .any(|marker| prefix.windows(marker.len()).any(|w| w == *marker))
+    }
 }
 
-fn decode_body(body: &[u8]) -> Result<Cow<str>, Box<dyn Error>> {
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-    let mut response = httparse::Response::new(&mut headers);
+/// The error for a body that isn't valid in `encoding`.
+fn decoding_error(encoding: &'static Encoding) -> Box<dyn Error> {
+    Box::new(io::Error::new(
+        ErrorKind::Other,
+        format!(
+            "Error decoding body with detected encoding {}",
+            encoding.name()
+        ),
+    ))
+}
 
-    let body = if let httparse::Status::Complete(body_offset) = response.parse(body)? {
-        &body[body_offset..]
-    } else {
-        warn!("Unable to parse headers, using entire request as body!");
-        body // Fall back to using the entire response: this is wrong, but probably OK
-    };
+/// Decode the beginning of a longer body. A character cut in half at the end
+/// is left out rather than treated as a decoding error. Like
+/// `Encoding::decode`, also gives the encoding used, which a BOM may
+/// override, and whether anything had to be replaced.
+fn decode_prefix(
+    encoding: &'static Encoding,
+    prefix: &[u8],
+) -> Result<(String, &'static Encoding, bool), Box<dyn Error>> {
+    let mut decoder = encoding.new_decoder();
+    let mut text = String::with_capacity(
+        decoder
+            .max_utf8_buffer_length(prefix.len())
+            .ok_or("Body too large to decode")?,
+    );
+    let (_, _, had_errors) = decoder.decode_to_string(prefix, &mut text, false);
+    Ok((text, decoder.encoding(), had_errors))
+}
+
+/// Decode the body of `payload`, guessing the encoding if the headers don't
+/// say. `tld`, the top-level domain of the page, helps with the guessing.
+/// Only the first `max_bytes` are decoded if set; forms are usually near the
+/// top. Bytes that aren't valid in the page's encoding are an error, unless
+/// `lossy`, when they are replaced. There's no point reserving space from
+/// `Content-Length`: the whole body is already in memory, UTF-8 is borrowed
+/// as is, and anything else is decoded into one buffer sized from the body.
+fn decode_body<'a>(
+    payload: &HttpPayload<'a>,
+    tld: Option<&str>,
+    max_bytes: Option<usize>,
+    lossy: bool,
+) -> Result<DecodedBody<'a>, Box<dyn Error>> {
+    let body = payload.body;
 
-    let document_encoding = get_encoding_by_header(headers).unwrap_or_else(|| {
+    let document_encoding = get_encoding_by_header(payload).unwrap_or_else(|| {
         let mut detector = EncodingDetector::new();
         const DETECTOR_CHUNK_SIZE_BYTES: usize = 1024;
 
@@ -321,82 +1829,492 @@ fn decode_body(body: &[u8]) -> Result<Cow<str>, Box<dyn Error>> {
         };
 
         detector.feed(subslice, is_last);
-        detector.guess(None, true)
+        detector.guess(tld.map(str::as_bytes), true)
     });
 
-    let (cow, decoder_used, had_errors) = document_encoding.decode(body);
-    if had_errors {
-        Err(Box::new(io::Error::new(
-            ErrorKind::Other,
-            format!(
-                "Error decoding body with detected encoding {}",
-                decoder_used.name()
-            ),
-        )))
-    } else {
-        Ok(cow)
+    let (text, truncated, decoder_used, had_errors) = match max_bytes {
+        Some(max_bytes) if body.len() > max_bytes => {
+            let (text, decoder_used, had_errors) =
+                decode_prefix(document_encoding, &body[..max_bytes])?;
+            (Cow::Owned(text), true, decoder_used, had_errors)
+        }
+        _ => {
+            let (cow, decoder_used, had_errors) = document_encoding.decode(body);
+            (cow, false, decoder_used, had_errors)
+        }
+    };
+    if had_errors && !lossy {
+        return Err(decoding_error(decoder_used));
     }
+
+    Ok(DecodedBody {
+        text,
+        content_language: payload
+            .header("Content-Language")
+            .map(|language| language.trim().to_owned())
+            .filter(|language| !language.is_empty()),
+        truncated,
+        encoding: decoder_used,
+        lossy: had_errors,
+    })
 }
 
-fn interesting_patterns<'a>(attributes: &'a tl::Attributes<'_>) -> impl Iterator<Item = &'a str> {
-    ["pattern", "data-val-regex-pattern", "ng-pattern"]
-        .into_iter()
-        .flat_map(|attr| {
-            attributes
-                .get(attr)
-                .flatten()
-                .and_then(|p| p.try_as_utf8_str())
-        })
+/// Which attribute a pattern came from, which usually tells us which
+/// framework, if any, put it there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PatternSource {
+    /// Plain HTML5 `pattern`.
+    #[serde(rename = "pattern")]
+    Html,
+    /// ASP.NET MVC unobtrusive validation, `data-val-regex-pattern`.
+    #[serde(rename = "data-val-regex-pattern")]
+    AspNet,
+    /// AngularJS `ng-pattern`.
+    #[serde(rename = "ng-pattern")]
+    Angular,
+    /// Any other attribute, given with `ProcessingOptions::pattern_attrs`.
+    #[serde(rename = "custom")]
+    Custom,
 }
 
-pub fn elements_with(form: &str, pattern: &str) -> Vec<String> {
-    let dom = tl::parse(form, tl::ParserOptions::default()).unwrap();
+impl PatternSource {
+    pub const ALL: [PatternSource; 3] = [
+        PatternSource::Html,
+        PatternSource::AspNet,
+        PatternSource::Angular,
+    ];
+
+    /// The attribute patterns from this source are in. `Custom` patterns
+    /// are in whichever attribute was configured, so it has none.
+    pub const fn attribute(self) -> &'static str {
+        match self {
+            PatternSource::Html => "pattern",
+            PatternSource::AspNet => "data-val-regex-pattern",
+            PatternSource::Angular => "ng-pattern",
+            PatternSource::Custom => "",
+        }
+    }
+
+    /// The source of patterns in `attribute`.
+    pub fn of_attribute(attribute: &str) -> PatternSource {
+        PatternSource::ALL
+            .into_iter()
+            .find(|source| source.attribute() == attribute)
+            .unwrap_or(PatternSource::Custom)
+    }
+}
+
+impl fmt::Display for PatternSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternSource::Custom => f.write_str("custom"),
+            source => f.write_str(source.attribute()),
+        }
+    }
+}
+
+/// Every pattern on an input, in the order of `pattern_attrs`. An input
+/// with several pattern attributes gives one entry for each, and all of
+/// them are read the same way.
+fn interesting_patterns<'a, S: AsRef<str>>(
+    attributes: &'a tl::Attributes<'_>,
+    pattern_attrs: &'a [S],
+) -> impl Iterator<Item = (PatternSource, &'a str)> + 'a {
+    pattern_attrs.iter().flat_map(move |attr| {
+        let attr = attr.as_ref();
+        attributes
+            .get(attr)
+            .flatten()
+            .and_then(|p| p.try_as_utf8_str())
+            .map(|p| (PatternSource::of_attribute(attr), p))
+    })
+}
+
+/// An element found by a search of a form, and the pattern on it, if any.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ElementMatch {
+    /// The element's markup, as stored.
+    pub html: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<PatternSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+impl ElementMatch {
+    fn new(form: &str, tag: &tl::HTMLTag, parser: &tl::Parser) -> Self {
+        let (start, end) = tag.boundaries(parser);
+        let (source, pattern) = interesting_patterns(tag.attributes(), &INTERESTING_ATTRS)
+            .next()
+            .map(|(source, pattern)| (source, pattern.to_owned()))
+            .unzip();
+        ElementMatch {
+            html: form[start..=end].to_owned(), // This is faster than innerHTML
+            source,
+            pattern,
+        }
+    }
+}
+
+/// The inputs of `form` with `pattern` in any of the pattern attributes.
+pub fn elements_with(form: &str, pattern: &str) -> Result<Vec<ElementMatch>, Box<dyn Error>> {
+    let dom = tl::parse(form, tl::ParserOptions::default())?;
     let parser = dom.parser();
-    let query = "input[pattern],input[data-val-regex-pattern],input[ng-pattern]";
-    if let Some(matches) = dom.query_selector(query) {
-        matches
-            .filter_map(|handle| handle.get(parser).and_then(|n| n.as_tag()))
-            .filter(|input_tag| {
-                interesting_patterns(input_tag.attributes())
-                    .find(|&tag_pattern| tag_pattern == pattern)
-                    .is_some()
+    let query = INTERESTING_INPUTS_QUERY.as_str();
+    let matches = dom
+        .query_selector(query)
+        .ok_or_else(|| format!("Invalid query: {query}"))?;
+    Ok(matches
+        .filter_map(|handle| handle.get(parser).and_then(|n| n.as_tag()))
+        .filter_map(|input_tag| {
+            let source = interesting_patterns(input_tag.attributes(), &INTERESTING_ATTRS)
+                .find(|(_, tag_pattern)| *tag_pattern == pattern)?
+                .0;
+            Some(ElementMatch {
+                source: Some(source),
+                pattern: Some(pattern.to_owned()),
+                ..ElementMatch::new(form, input_tag, parser)
             })
-            .map(|input_tag| {
-                let (start, end) = input_tag.boundaries(parser);
-                let tag_text = &form[start..=end]; // This is faster than innerHTML
-                tag_text.to_owned()
-            })
-            .collect()
-    } else {
-        panic!("Invalid query: {query}");
+        })
+        .collect())
+}
+
+/// The elements of `form` matched by any of the CSS selectors in `queries`,
+/// in document order. Elements matched by several queries are only included
+/// once.
+pub fn elements_matching_query(
+    form: &str,
+    queries: &[String],
+) -> Result<Vec<ElementMatch>, Box<dyn Error>> {
+    let dom = tl::parse(form, tl::ParserOptions::default())?;
+    let parser = dom.parser();
+    let mut matched = BTreeMap::new();
+    for query in queries {
+        let matches = dom
+            .query_selector(query)
+            .ok_or_else(|| format!("Invalid query: {query}"))?;
+        for tag in matches.filter_map(|handle| handle.get(parser).and_then(|n| n.as_tag())) {
+            matched
+                .entry(tag.boundaries(parser))
+                .or_insert_with(|| ElementMatch::new(form, tag, parser));
+        }
     }
+    Ok(matched.into_values().collect())
 }
 
-pub fn patterns_in(form: &str) -> Vec<String> {
-    let dom = tl::parse(form, tl::ParserOptions::default()).unwrap();
+/// The `action` of the (outermost) form in `form`, i.e. where it is submitted.
+pub fn form_action(form: &str) -> Option<String> {
+    let dom = tl::parse(form, tl::ParserOptions::default()).ok()?;
     let parser = dom.parser();
+    dom.query_selector("form")?
+        .next()?
+        .get(parser)?
+        .as_tag()?
+        .attributes()
+        .get("action")
+        .flatten()
+        .and_then(|action| action.try_as_utf8_str())
+        .map(|action| action.to_owned())
+}
 
+/// A validation pattern found on an `<input>`, and what else we know about
+/// that input.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatternedInput {
+    pub pattern: String,
+    pub source: PatternSource,
+    /// Whether the input is also `required`, which makes the pattern a hard
+    /// gate rather than something that only applies once the field is filled.
+    pub required: bool,
+    /// The input's placeholder, which often hints at the expected format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+    /// The input's `inputmode`, e.g. `numeric`, which picks the on-screen
+    /// keyboard on mobile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inputmode: Option<String>,
+}
+
+/// The patterned inputs of `form`, which is usually a bare `<form>...</form>`
+/// fragment as stored by `cc-get` rather than a whole page. That needs no
+/// wrapping: `tl` keeps every element of a fragment, and its queries look
+/// through all of them rather than only those below an `<html>` root.
+pub fn patterned_inputs(form: &str) -> Result<Vec<PatternedInput>, Box<dyn Error>> {
+    patterned_inputs_with(form, &INTERESTING_ATTRS)
+}
+
+/// Like `patterned_inputs`, but reading patterns from `pattern_attrs`, e.g.
+/// those a summary was written with.
+pub fn patterned_inputs_with<S: AsRef<str>>(
+    form: &str,
+    pattern_attrs: &[S],
+) -> Result<Vec<PatternedInput>, Box<dyn Error>> {
+    let dom = tl::parse(form, tl::ParserOptions::default())?;
+    let parser = dom.parser();
+
+    let query = pattern_attrs
+        .iter()
+        .map(|attr| format!("input[{}]", attr.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",");
     let inputs = dom
-        .query_selector("input[pattern],input[data-val-regex-pattern],input[ng-pattern]")
-        .unwrap()
+        .query_selector(&query)
+        .ok_or_else(|| format!("Invalid query for interesting inputs: {query}"))?
         .filter_map(|handle| handle.get(parser).and_then(|n| n.as_tag()));
 
     let mut patterns = Vec::default();
     for tag in inputs {
         let attributes = tag.attributes();
-        patterns.extend(interesting_patterns(attributes).map(|a| a.to_owned()));
+        let required = attributes.contains("required");
+        let placeholder = attributes
+            .get("placeholder")
+            .flatten()
+            .and_then(|p| p.try_as_utf8_str());
+        let inputmode = attributes
+            .get("inputmode")
+            .flatten()
+            .and_then(|mode| mode.try_as_utf8_str());
+        patterns.extend(interesting_patterns(attributes, pattern_attrs).map(
+            |(source, pattern)| PatternedInput {
+                pattern: pattern.to_owned(),
+                source,
+                required,
+                placeholder: placeholder.map(|p| p.to_owned()),
+                inputmode: inputmode.map(|mode| mode.to_owned()),
+            },
+        ));
+    }
+
+    Ok(patterns)
+}
+
+/// The patterns in `form`, and which attribute each came from.
+pub fn patterns_in(form: &str) -> Result<Vec<(PatternSource, String)>, Box<dyn Error>> {
+    patterns_in_with(form, &INTERESTING_ATTRS)
+}
+
+/// Like `patterns_in`, but reading patterns from `pattern_attrs`.
+pub fn patterns_in_with<S: AsRef<str>>(
+    form: &str,
+    pattern_attrs: &[S],
+) -> Result<Vec<(PatternSource, String)>, Box<dyn Error>> {
+    Ok(patterned_inputs_with(form, pattern_attrs)?
+        .into_iter()
+        .map(|input| (input.source, input.pattern))
+        .collect())
+}
+
+/// Like `patterns_in`, but with the markup of the `<input>` each pattern
+/// came from instead of its source, for checking the extraction by hand.
+pub fn patterns_in_detailed(form: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let dom = tl::parse(form, tl::ParserOptions::default())?;
+    let parser = dom.parser();
+
+    let inputs = dom
+        .query_selector(&INTERESTING_INPUTS_QUERY)
+        .ok_or("Invalid query for interesting inputs")?
+        .filter_map(|handle| handle.get(parser).and_then(|n| n.as_tag()));
+
+    let mut patterns = Vec::default();
+    for tag in inputs {
+        let (start, end) = tag.boundaries(parser);
+        let input_html = &form[start..=end];
+        patterns.extend(
+            interesting_patterns(tag.attributes(), &INTERESTING_ATTRS)
+                .map(|(_, pattern)| (pattern.to_owned(), input_html.to_owned())),
+        );
+    }
+
+    Ok(patterns)
+}
+
+/// A `<select>` and the values it allows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelectOptions {
+    pub name: Option<String>,
+    /// Each option's `value`, or its text if it has none.
+    pub values: Vec<String>,
+}
+
+pub fn select_options(form: &str) -> Result<Vec<SelectOptions>, Box<dyn Error>> {
+    let dom = tl::parse(form, tl::ParserOptions::default())?;
+    let parser = dom.parser();
+
+    let selects = dom
+        .query_selector("select")
+        .ok_or("Invalid query for selects")?
+        .filter_map(|handle| handle.get(parser).and_then(|n| n.as_tag()));
+
+    let mut result = Vec::default();
+    for select in selects {
+        let name = select
+            .attributes()
+            .get("name")
+            .flatten()
+            .and_then(|n| n.try_as_utf8_str())
+            .map(|n| n.to_owned());
+        let values = select
+            .query_selector(parser, "option")
+            .ok_or("Invalid query for options")?
+            .filter_map(|handle| handle.get(parser).and_then(|n| n.as_tag()))
+            .map(|option| {
+                match option
+                    .attributes()
+                    .get("value")
+                    .flatten()
+                    .and_then(|v| v.try_as_utf8_str())
+                {
+                    Some(value) => value.to_owned(),
+                    None => option.inner_text(parser).trim().to_owned(),
+                }
+            })
+            .collect();
+        result.push(SelectOptions { name, values });
+    }
+
+    Ok(result)
+}
+
+/// Elements that never have content, so `<input/>` and `<input>` mean the
+/// same thing in HTML.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Rewrite XML-style self-closing tags like `<select name="x"/>` as
+/// `<select name="x"></select>`. An HTML parser reads the former as an
+/// opening tag, and would put everything that follows inside it.
+fn expand_self_closing(xhtml: &str) -> String {
+    let mut out = String::with_capacity(xhtml.len());
+    let mut rest = xhtml;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // Comments may contain anything, including unbalanced quotes.
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map_or(rest.len(), |end| end + "-->".len());
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        // Find the end of the tag, skipping '>' inside quoted attributes.
+        let mut quote = None;
+        let end = rest.char_indices().skip(1).find(|&(_, c)| match quote {
+            Some(q) if c == q => {
+                quote = None;
+                false
+            }
+            Some(_) => false,
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                false
+            }
+            None => c == '>',
+        });
+        let Some((end, _)) = end else {
+            break;
+        };
+        let tag = &rest[..=end];
+        rest = &rest[end + 1..];
+
+        let name: String = tag[1..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == ':' || *c == '-')
+            .collect();
+        let inner = tag[..tag.len() - 1].trim_end();
+        if !name.is_empty()
+            && inner.ends_with('/')
+            && !VOID_ELEMENTS.contains(&name.to_ascii_lowercase().as_str())
+        {
+            out.push_str(&inner[..inner.len() - 1]);
+            out.push_str("></");
+            out.push_str(&name);
+            out.push('>');
+        } else {
+            out.push_str(tag);
+        }
     }
+    out.push_str(rest);
+    out
+}
 
-    patterns
+/// The forms with validation patterns in a raw HTTP response, as stored in
+/// a WARC response record. This is the extraction `cc-get` does for every
+/// page, minus the WARC handling.
+pub fn interesting_forms_in(http_response: &[u8]) -> Result<Vec<String>, Box<dyn Error>> {
+    let payload = HttpPayload::parse(http_response)?;
+    Ok(extract_forms(&payload, None, &ProcessingOptions::default())?.interesting)
 }
 
-fn extract_forms(content: &[u8]) -> Result<(i64, Vec<String>), Box<dyn Error>> {
-    let body = decode_body(content)?;
+struct PageForms {
+    nr_forms: i64,
+    interesting: Vec<String>,
+    /// One per form in `interesting`.
+    details: Vec<FormDetails>,
+    language: Option<String>,
+    /// Interesting forms we only got the opening tag of, and skipped.
+    nr_truncated_forms: i64,
+    /// Only the beginning of the page was decoded.
+    body_truncated: bool,
+    nr_iframes: usize,
+    /// Patterns across the interesting forms, including collapsed ones.
+    nr_patterns: usize,
+    nr_distinct_patterns: usize,
+    /// Interesting forms left out as all their patterns were already seen
+    /// on the page.
+    nr_collapsed_forms: i64,
+    /// Only set with `ProcessingOptions::detect_amp`.
+    is_amp: bool,
+    nr_amp_forms: i64,
+    /// The name of the encoding the page was decoded with.
+    encoding: &'static str,
+    lossy_decode: bool,
+}
+
+/// Extract the forms of a page, counting those with an input carrying any of
+/// `options.pattern_attrs` as interesting.
+fn extract_forms(
+    payload: &HttpPayload,
+    tld: Option<&str>,
+    options: &ProcessingOptions,
+) -> Result<PageForms, Box<dyn Error>> {
+    let DecodedBody {
+        text: body,
+        content_language,
+        truncated: body_truncated,
+        encoding,
+        lossy: lossy_decode,
+    } = decode_body(payload, tld, options.max_body_bytes, options.lossy_decode)?;
+    let body = if payload.is_xhtml() {
+        Cow::Owned(expand_self_closing(&body))
+    } else {
+        body
+    };
     let dom = tl::parse(&body, tl::ParserOptions::default()).unwrap();
     let parser = dom.parser();
 
+    let html = dom
+        .query_selector("html")
+        .and_then(|mut html| html.next())
+        .and_then(|handle| handle.get(parser))
+        .and_then(|node| node.as_tag());
+    // AMP documents mark their root with `amp` or `⚡`.
+    let is_amp = options.detect_amp
+        && html.map_or(false, |html| {
+            html.attributes().contains("amp") || html.attributes().contains("⚡")
+        });
+    let mut nr_amp_forms = 0;
+
     let mut nr_forms = 0;
+    let mut nr_truncated_forms = 0;
     let mut interesting_forms: Vec<String> = Vec::new();
+    let mut details = Vec::new();
+    let mut nr_patterns = 0;
+    let mut page_patterns = HashSet::new();
+    let mut nr_collapsed_forms = 0;
     let forms = dom
         .query_selector("form")
         .unwrap()
@@ -404,67 +2322,1385 @@ fn extract_forms(content: &[u8]) -> Result<(i64, Vec<String>), Box<dyn Error>> {
 
     for form in forms {
         nr_forms += 1;
+        // amp-form submits with `action-xhr` and reports validation its own
+        // way, which can also show up outside AMP documents.
+        if options.detect_amp
+            && (is_amp
+                || AMP_FORM_ATTRS
+                    .iter()
+                    .any(|attr| form.attributes().contains(attr)))
+        {
+            nr_amp_forms += 1;
+        }
 
-        if form
-            .children()
+        let mut nr_patterned_inputs = 0;
+        let mut form_patterns = Vec::new();
+        let children = form.children();
+        let inputs = children
             .all(parser)
             .iter()
             .filter_map(|e| e.as_tag())
-            .any(|tag| {
-                let attributes = tag.attributes();
-                tag.name().as_bytes() == b"input"
-                    && (attributes.contains("pattern")
-                        || attributes.contains("data-val-regex-pattern")
-                        || attributes.contains("ng-pattern"))
-            })
-        {
+            .filter(|tag| tag.name().as_bytes() == b"input");
+        for input in inputs {
+            let attributes = input.attributes();
+            let mut patterned = false;
+            for attr in &options.pattern_attrs {
+                if let Some(value) = attributes.get(attr.as_str()) {
+                    patterned = true;
+                    form_patterns.extend(value.and_then(|v| v.try_as_utf8_str()));
+                }
+            }
+            nr_patterned_inputs += patterned as usize;
+        }
+
+        if nr_patterned_inputs > 0 {
             let (start, end) = form.boundaries(parser);
             let tag_text = body[start..=end].to_owned();
             if !tag_text.contains("</form>") {
                 // For some reason, we sometimes only get the opening tag.
-                return Err("No closing tag in form: assuming broken HTML".into());
+                trace!("No closing tag in form: assuming broken HTML");
+                nr_truncated_forms += 1;
+                continue;
             }
+            nr_patterns += form_patterns.len();
+            let all_seen = !form_patterns.is_empty()
+                && form_patterns
+                    .iter()
+                    .all(|pattern| page_patterns.contains(*pattern));
+            page_patterns.extend(form_patterns);
+            if options.collapse_duplicate_forms && all_seen {
+                trace!("Collapsing form with only patterns already seen on the page");
+                nr_collapsed_forms += 1;
+                continue;
+            }
+            let attribute = |name: &str| {
+                form.attributes()
+                    .get(name)
+                    .flatten()
+                    .and_then(|value| value.try_as_utf8_str())
+                    .map(|value| value.trim().to_owned())
+                    .filter(|value| !value.is_empty())
+            };
+            details.push(FormDetails {
+                nr_patterned_inputs,
+                nr_bytes: tag_text.len(),
+                id: attribute("id"),
+                class: attribute("class"),
+            });
             interesting_forms.push(tag_text);
         }
     }
-    Ok((nr_forms, interesting_forms))
+
+    let html_lang = html
+        .and_then(|html| html.attributes().get("lang").flatten())
+        .and_then(|lang| lang.try_as_utf8_str())
+        .map(|lang| lang.trim().to_owned())
+        .filter(|lang| !lang.is_empty());
+
+    let nr_iframes = dom
+        .query_selector("iframe")
+        .map_or(0, |iframes| iframes.count());
+
+    Ok(PageForms {
+        nr_forms,
+        interesting: interesting_forms,
+        details,
+        language: html_lang.or(content_language),
+        nr_truncated_forms,
+        body_truncated,
+        nr_iframes,
+        nr_patterns,
+        nr_distinct_patterns: page_patterns.len(),
+        nr_collapsed_forms,
+        is_amp,
+        nr_amp_forms,
+        encoding: encoding.name(),
+        lossy_decode,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessingOptions {
+    pub writer: WriterOptions,
+    /// Warn periodically about WARCs taking longer than this to process.
+    pub heartbeat_after: Option<Duration>,
+    /// Only process records captured at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only process records captured before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// Store what could be read of WARCs that fail part-way through, rather
+    /// than treating them as failed.
+    pub keep_partial: bool,
+    /// Skip pages that weren't captured with a 2xx status, e.g. soft 404s.
+    pub only_success: bool,
+    /// Tell the encoding detector which top-level domain a page is from,
+    /// which helps it with regional encodings.
+    pub tld_hint: bool,
+    /// Keep the first few per-record extraction errors in each summary,
+    /// for debugging.
+    pub record_errors: bool,
+    /// The attributes of `<input>` elements that make a form interesting.
+    pub pattern_attrs: Vec<String>,
+    /// Only search the first this many bytes of each page for forms, to
+    /// bound memory use on huge pages.
+    pub max_body_bytes: Option<usize>,
+    /// Only process each response with this probability, for quick
+    /// approximate runs.
+    pub sample: Option<f64>,
+    /// Seeds the sampling, together with the WARC's URL, so runs can be
+    /// repeated.
+    pub sample_seed: u64,
+    /// Stop the run if more than this fraction of pages can't be decoded,
+    /// which suggests something is systematically wrong.
+    pub max_error_rate: Option<f64>,
+    /// Leave out interesting forms whose patterns all already occurred in
+    /// earlier forms on the same page, e.g. a component repeated by a
+    /// template.
+    pub collapse_duplicate_forms: bool,
+    /// Count AMP pages and amp-form forms, which validate input their own
+    /// way.
+    pub detect_amp: bool,
+    /// How many WARCs may be downloaded and processed at once, as each one
+    /// takes up memory. Records within a WARC are still processed in
+    /// parallel. No limit beyond the thread count if `None`.
+    pub max_concurrent_warcs: Option<usize>,
+    /// Replace bytes that aren't valid in a page's encoding and extract its
+    /// forms anyway, rather than giving up on the page.
+    pub lossy_decode: bool,
+}
+
+impl Default for ProcessingOptions {
+    fn default() -> Self {
+        ProcessingOptions {
+            writer: WriterOptions::default(),
+            heartbeat_after: Some(Duration::from_secs(DEFAULT_HEARTBEAT_S)),
+            since: None,
+            until: None,
+            keep_partial: false,
+            only_success: false,
+            tld_hint: true,
+            record_errors: false,
+            pattern_attrs: default_pattern_attrs(),
+            max_body_bytes: None,
+            sample: None,
+            sample_seed: 0,
+            max_error_rate: None,
+            collapse_duplicate_forms: false,
+            detect_amp: false,
+            max_concurrent_warcs: None,
+            lossy_decode: false,
+        }
+    }
+}
+
+impl ProcessingOptions {
+    /// `pattern_attrs` as recorded in summaries: left out if they are the
+    /// defaults.
+    fn stored_pattern_attrs(&self) -> Vec<String> {
+        if self.pattern_attrs == default_pattern_attrs() {
+            Vec::new()
+        } else {
+            self.pattern_attrs.clone()
+        }
+    }
+
+    /// Whether `record` was captured inside the `since`/`until` window.
+    /// Records without a readable `WARC-Date` are only kept if no window
+    /// is set.
+    fn in_date_range(&self, record: &WarcRecord) -> bool {
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        let Some(date) = warc_date(record) else {
+            return false;
+        };
+        self.since.map_or(true, |since| date >= since)
+            && self.until.map_or(true, |until| date < until)
+    }
+}
+
+/// When the record was captured, from its `WARC-Date` header.
+fn warc_date(record: &WarcRecord) -> Option<DateTime<Utc>> {
+    let date = record.header.get(&WARC_DATE)?;
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .ok()
+}
+
+/// Read every record of a gzipped WARC. WARCs are a concatenation of gzip
+/// members, one per record, so this needs a `MultiGzDecoder`; a plain
+/// `GzDecoder` would stop after the first record.
+///
+/// Reading stops at the first error, which is logged and flagged in
+/// `failed`: after a broken gzip member there is nothing sensible left to
+/// read.
+fn read_records<R: Read>(reader: R, failed: Arc<AtomicBool>) -> impl Iterator<Item = WarcRecord> {
+    let mut nr_read = 0u64;
+    WarcReader::new(BufReader::new(MultiGzDecoder::new(BufReader::new(reader)))).map_while(
+        move |record| match record {
+            Ok(record) => {
+                nr_read += 1;
+                Some(record)
+            }
+            Err(e) => {
+                warn!("Stopped reading WARC after {} records: {:?}", nr_read, e);
+                failed.store(true, Ordering::Relaxed);
+                None
+            }
+        },
+    )
+}
+
+/// Counts the bytes read through it, so we can tell if a download was cut
+/// short.
+struct CountingReader<R> {
+    inner: R,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// How much of a download has been read, and how much the server said
+/// there was.
+struct DownloadProgress {
+    bytes_read: Arc<AtomicU64>,
+    content_length: Option<u64>,
+    /// Set if reading records failed part-way through.
+    stream_failed: Arc<AtomicBool>,
+}
+
+impl DownloadProgress {
+    /// Fails if we read less than the server promised.
+    fn check_complete(&self) -> Result<(), ProcessError> {
+        if self.stream_failed.load(Ordering::Relaxed) {
+            return Err(ProcessError::Stream);
+        }
+        let read = self.bytes_read.load(Ordering::Relaxed);
+        match self.content_length {
+            Some(expected) if read < expected => Err(ProcessError::Truncated { expected, read }),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn get_records(
+    url: &str,
+    mut client: Client,
+) -> Result<(impl Iterator<Item = WarcRecord> + Send, DownloadProgress), ProcessError> {
+    // The client has already retried server errors, so whatever status is
+    // left is final.
+    let response = client.get(url)?;
+    if !response.status().is_success() {
+        return Err(ProcessError::Status(response.status()));
+    }
+    let progress = DownloadProgress {
+        bytes_read: Arc::new(AtomicU64::new(0)),
+        content_length: response.content_length(),
+        stream_failed: Arc::new(AtomicBool::new(false)),
+    };
+    let records = read_records(
+        CountingReader {
+            inner: response,
+            bytes_read: progress.bytes_read.clone(),
+        },
+        progress.stream_failed.clone(),
+    );
+    Ok((records, progress))
+}
+
+/// How many times to try a WARC before giving up on it.
+const WARC_ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+pub enum ProcessError {
+    Http(reqwest::Error),
+    /// The server answered the WARC request with a non-success status, e.g.
+    /// a 404 for a WARC that isn't there.
+    Status(StatusCode),
+    /// The download ended before `Content-Length` bytes were read.
+    Truncated {
+        expected: u64,
+        read: u64,
+    },
+    /// The WARC could not be read to the end, e.g. because of a broken gzip
+    /// stream.
+    Stream,
+}
+
+impl ProcessError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            ProcessError::Http(e) => e.is_timeout() || e.is_connect() || e.is_body(),
+            ProcessError::Truncated { .. } | ProcessError::Stream => true,
+            ProcessError::Status(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Http(e) => write!(f, "{}", e),
+            ProcessError::Status(status) => write!(f, "Server answered {}", status),
+            ProcessError::Truncated { expected, read } => {
+                write!(f, "Download truncated: read {} of {} bytes", read, expected)
+            }
+            ProcessError::Stream => write!(f, "Error reading WARC records"),
+        }
+    }
+}
+
+impl Error for ProcessError {}
+
+impl From<reqwest::Error> for ProcessError {
+    fn from(e: reqwest::Error) -> Self {
+        ProcessError::Http(e)
+    }
+}
+
+fn is_response(record: &WarcRecord) -> bool {
+    record.header.get(&WARC_TYPE) == Some(&"response".into())
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is the same everywhere, and across
+/// Rust releases.
+pub fn fnv_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The key `seed` gives the `i`th form of `url` when sampling. FNV alone
+/// gives the forms of one page nearby keys, so its output is mixed with the
+/// SplitMix64 finaliser to spread them out.
+pub fn sample_key(seed: u64, url: &str, i: usize) -> u64 {
+    let mut key = fnv_hash(&format!("{seed}\n{url}\n{i}"));
+    key = (key ^ (key >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    key = (key ^ (key >> 27)).wrapping_mul(0x94d049bb133111eb);
+    key ^ (key >> 31)
+}
+
+/// Process the records of a WARC in parallel. Note that only the download
+/// itself touches the network: clones of `client` share one connection pool,
+/// and each WARC uses a single connection from it however many threads are
+/// parsing its records.
+fn process_warc(
+    url: &str,
+    client: Client,
+    options: &ProcessingOptions,
+) -> Result<ArchiveSummary, ProcessError> {
+    let (records, progress) = get_records(url, client)?;
+
+    // Decide which responses to sample before the records are spread over
+    // threads, so the same ones are picked every time.
+    let sample = options.sample;
+    let mut rng = StdRng::seed_from_u64(options.sample_seed ^ fnv_hash(url));
+    let records = records.map(move |record| {
+        let sampled_out = sample.map_or(false, |p| is_response(&record) && !rng.gen_bool(p));
+        (record, sampled_out)
+    });
+
+    let started_at = Instant::now();
+    let records_seen = AtomicU64::new(0);
+
+    let summary = thread::scope(|scope| {
+        // Say something about this WARC every so often from a thread of its
+        // own, so that we also hear about it while the download is stalled.
+        // Dropping `done` stops it.
+        let (done, stop) = mpsc::channel::<()>();
+        if let Some(interval) = options.heartbeat_after {
+            let records_seen = &records_seen;
+            scope.spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) =
+                    stop.recv_timeout(interval.max(Duration::from_secs(1)))
+                {
+                    warn!(
+                        "Still processing {}: {} records so far, {}s elapsed",
+                        url,
+                        records_seen.load(Ordering::Relaxed),
+                        started_at.elapsed().as_secs()
+                    );
+                }
+            });
+        }
+
+        let summary = records
+            .par_bridge()
+            .inspect(|_| {
+                records_seen.fetch_add(1, Ordering::Relaxed);
+            })
+            .filter(|(record, _)| is_response(record))
+            .flat_map(|(record, sampled_out)| {
+                if sampled_out {
+                    Some(ArchiveSummary {
+                        nr_sampled_out: 1,
+                        ..Default::default()
+                    })
+                } else if options.in_date_range(&record) {
+                    ArchiveSummary::from_record(record, options)
+                } else {
+                    Some(ArchiveSummary {
+                        nr_outside_date_range: 1,
+                        ..Default::default()
+                    })
+                }
+            })
+            .reduce(ArchiveSummary::default, |a, b| a.merge(b));
+        drop(done);
+        summary
+    });
+
+    // A truncated WARC still parses, it just has fewer records; don't let
+    // it pass as complete.
+    let partial = match progress.check_complete() {
+        Err(ProcessError::Stream) if options.keep_partial => {
+            warn!("Keeping partial results for {}", url);
+            true
+        }
+        result => {
+            result?;
+            false
+        }
+    };
+
+    let nr_records_seen = records_seen.load(Ordering::Relaxed) as i64;
+    info!("Done with WARC ID {} ({} records)", &url, nr_records_seen);
+
+    let without_html = nr_records_seen >= MIN_RECORDS_FOR_HTML
+        && summary.nr_html_records() == 0
+        && summary.nr_sampled_out == 0;
+    if without_html {
+        warn!(
+            "No HTML in {} despite {} records ({} skipped as non-HTML)",
+            url, nr_records_seen, summary.nr_skipped_non_html
+        );
+    }
+
+    Ok(ArchiveSummary {
+        nr_records_seen,
+        nr_warcs_without_html: without_html as i64,
+        nr_partial_warcs: partial as i64,
+        pattern_attrs: options.stored_pattern_attrs(),
+        ..summary
+    })
 }
 
-fn process_warc(url: &str, client: Client) -> Result<ArchiveSummary, reqwest::Error> {
-    let warc_reader = WarcReader::new(BufReader::new(MultiGzDecoder::new(BufReader::new(
-        client.clone().get(url)?.error_for_status()?,
-    ))));
+/// A WARC that could not be processed, and why.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FailedWarc {
+    pub url: String,
+    pub reason: String,
+    /// The failure may well be temporary, so trying again later could work.
+    pub retryable: bool,
+}
 
-    let summary = warc_reader
-        .par_bridge()
-        .filter_map(|r| r.ok())
-        .filter(|r| r.header.get(&WARC_TYPE) == Some(&"response".into()))
-        .flat_map(ArchiveSummary::from_record)
-        .reduce(ArchiveSummary::default, |a, b| a.merge(b));
+/// How one WARC of a run went, as passed to the callback of
+/// `process_warcs_with_progress` once it's done.
+#[derive(Debug, Clone, Serialize)]
+pub struct WarcProgress {
+    pub warc: String,
+    /// `ok`, `skipped` or `failed`.
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Only for WARCs that were processed.
+    #[serde(flatten)]
+    pub counts: Option<WarcCounts>,
+}
 
-    info!("Done with WARC ID {}", &url);
+/// The main counts of a processed WARC's summary.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WarcCounts {
+    pub urls: i64,
+    pub urls_with_patterns: i64,
+    pub forms: i64,
+    pub forms_with_patterns: i64,
+    pub undecodable: i64,
+}
 
-    Ok(summary)
+impl WarcCounts {
+    fn of(summary: &ArchiveSummary) -> Self {
+        WarcCounts {
+            urls: summary.total_urls(),
+            urls_with_patterns: summary.urls_with_pattern_forms.len() as i64,
+            forms: summary.total_forms(),
+            forms_with_patterns: summary.forms_with_patterns(),
+            undecodable: summary.nr_unknown_encoding,
+        }
+    }
 }
 
-pub fn process_warcs(urls: Vec<String>, client: Client) {
-    let writer = Arc::new(Mutex::new(AnalysisWriter::new()));
+/// How the WARCs of a run fared.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunStats {
+    pub succeeded: u64,
+    pub failed: u64,
+    /// The WARCs that failed, for trying again.
+    pub failed_warcs: Vec<FailedWarc>,
+    /// WARCs that were not attempted, e.g. because they were already
+    /// processed, or that the server wouldn't serve, e.g. with a 404.
+    pub skipped: u64,
+    /// The run was stopped early, as too many pages couldn't be decoded.
+    pub aborted: bool,
+}
 
-    urls.into_par_iter()
-        .map(move |url| {
-            let summary = process_warc(&url, client.clone());
-            (url, summary)
+impl RunStats {
+    pub fn merge(self, other: RunStats) -> RunStats {
+        let mut failed_warcs = self.failed_warcs;
+        failed_warcs.extend(other.failed_warcs);
+        RunStats {
+            succeeded: self.succeeded + other.succeeded,
+            failed: self.failed + other.failed,
+            failed_warcs,
+            skipped: self.skipped + other.skipped,
+            aborted: self.aborted || other.aborted,
+        }
+    }
+}
+
+/// `RunStats` as written to `FormsDir::run_report_fn`.
+#[derive(Serialize)]
+struct RunReport<'a> {
+    attempted: u64,
+    succeeded: u64,
+    skipped: u64,
+    failed: u64,
+    aborted: bool,
+    failed_warcs: &'a [FailedWarc],
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} WARCs succeeded, {} failed, {} skipped",
+            self.succeeded, self.failed, self.skipped
+        )?;
+        if self.aborted {
+            write!(f, " (stopped early)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the summary of the pages of `urls` in `archive`, fetched by
+/// `process_urls`, is stored. Named after the URLs, so that the same list
+/// is only fetched once.
+pub fn targeted_key(archive: &str, urls: &[String]) -> String {
+    format!("cdx/{}/{:016x}", archive, fnv_hash(&urls.join("\n")))
+}
+
+/// Look `urls` up in the CDX index of `archive` and process only their
+/// records, fetched by byte range, instead of whole WARCs. The pages are
+/// summarised together, under `targeted_key`, but only if every URL could be
+/// fetched, so that a list with failures is tried again in full next time.
+/// The returned counts are of URLs rather than WARCs.
+pub fn process_urls(
+    urls: Vec<String>,
+    archive: &str,
+    client: Client,
+    options: ProcessingOptions,
+) -> RunStats {
+    let key = targeted_key(archive, &urls);
+    let fetch = |url: &str| -> Result<ArchiveSummary, Box<dyn Error>> {
+        let mut client = client.clone();
+        let entries = client.lookup(archive, url)?;
+        if entries.is_empty() {
+            return Err("Not in the index".into());
+        }
+        let mut summary = ArchiveSummary::default();
+        for entry in entries {
+            let record = client.get_record(&entry)?;
+            if !is_response(&record) {
+                continue;
+            }
+            let page = if options.in_date_range(&record) {
+                ArchiveSummary::from_record(record, &options)
+            } else {
+                Some(ArchiveSummary {
+                    nr_outside_date_range: 1,
+                    ..Default::default()
+                })
+            };
+            summary = summary.merge(page.unwrap_or_default());
+        }
+        Ok(summary)
+    };
+
+    let (summary, stats) = urls
+        .into_par_iter()
+        .map(|url| match fetch(&url) {
+            Ok(summary) => (
+                summary,
+                RunStats {
+                    succeeded: 1,
+                    ..Default::default()
+                },
+            ),
+            Err(e) => {
+                warn!("Unable to fetch {}: {}", url, e);
+                (
+                    ArchiveSummary::default(),
+                    RunStats {
+                        failed: 1,
+                        failed_warcs: vec![FailedWarc {
+                            url,
+                            reason: e.to_string(),
+                            retryable: false,
+                        }],
+                        ..Default::default()
+                    },
+                )
+            }
         })
-        .for_each(|(url, summary)| match summary {
-            Ok(summary) => {
+        .reduce(
+            || (ArchiveSummary::default(), RunStats::default()),
+            |(summary_a, stats_a), (summary_b, stats_b)| {
+                (summary_a.merge(summary_b), stats_a.merge(stats_b))
+            },
+        );
+
+    if stats.failed > 0 {
+        warn!(
+            "Not storing {}: {} of {} URLs failed",
+            key,
+            stats.failed,
+            stats.failed + stats.succeeded
+        );
+    } else if stats.succeeded > 0 {
+        let summary = ArchiveSummary {
+            pattern_attrs: options.stored_pattern_attrs(),
+            ..summary
+        };
+        AnalysisWriter::with_options(options.writer.clone())
+            .write(key, summary)
+            .expect("Could not write URL summary!");
+    }
+    stats
+}
+
+/// Process a WARC, trying again on errors that may well be temporary.
+fn process_warc_with_retries(
+    url: &str,
+    client: &Client,
+    options: &ProcessingOptions,
+) -> Result<ArchiveSummary, ProcessError> {
+    let mut attempt = 1;
+    loop {
+        match process_warc(url, client.clone(), options) {
+            Err(e) if e.is_retryable() && attempt < WARC_ATTEMPTS => {
+                warn!(
+                    "Attempt {} of {} at {} failed: {}. Retrying",
+                    attempt, WARC_ATTEMPTS, url, e
+                );
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub fn process_warcs(urls: Vec<String>, client: Client, options: ProcessingOptions) -> RunStats {
+    process_warcs_with_progress(urls, client, options, |_| {})
+}
+
+/// Like `process_warcs`, but calls `on_done` as each WARC is done, e.g. to
+/// follow a run as it goes.
+pub fn process_warcs_with_progress(
+    urls: Vec<String>,
+    client: Client,
+    options: ProcessingOptions,
+    on_done: impl Fn(&WarcProgress) + Sync,
+) -> RunStats {
+    let writer = Arc::new(Mutex::new(AnalysisWriter::with_options(
+        options.writer.clone(),
+    )));
+
+    let total = urls.len();
+    let started_at = Instant::now();
+    let nr_done = AtomicU64::new(0);
+    let max_error_rate = options.max_error_rate;
+    let nr_pages = AtomicU64::new(0);
+    let nr_undecodable = AtomicU64::new(0);
+    let aborted = AtomicBool::new(false);
+
+    let process = |url: String| {
+        let summary = if aborted.load(Ordering::Relaxed) {
+            None
+        } else {
+            Some(process_warc_with_retries(&url, &client, &options))
+        };
+
+        let done = nr_done.fetch_add(1, Ordering::Relaxed) + 1;
+        let elapsed_s = started_at.elapsed().as_secs();
+        let eta_s = elapsed_s * (total as u64 - done) / done;
+        info!("{} of {} WARCs done, about {}s to go", done, total, eta_s);
+
+        match summary {
+            None => RunStats {
+                skipped: 1,
+                ..Default::default()
+            },
+            Some(Ok(summary)) => {
+                if let Some(max_error_rate) = max_error_rate {
+                    let undecodable = nr_undecodable
+                        .fetch_add(summary.nr_unknown_encoding as u64, Ordering::Relaxed)
+                        + summary.nr_unknown_encoding as u64;
+                    let pages = nr_pages.fetch_add(summary.total_urls() as u64, Ordering::Relaxed)
+                        + summary.total_urls() as u64;
+                    let error_rate = undecodable as f64 / pages as f64;
+                    if pages >= MIN_PAGES_FOR_ERROR_RATE
+                        && error_rate > max_error_rate
+                        && !aborted.swap(true, Ordering::Relaxed)
+                    {
+                        error!(
+                            "{:.1}% of {} pages could not be decoded, more than the allowed {:.1}%. Stopping",
+                            100.0 * error_rate,
+                            pages,
+                            100.0 * max_error_rate
+                        );
+                    }
+                }
+                on_done(&WarcProgress {
+                    warc: url.clone(),
+                    outcome: "ok",
+                    reason: None,
+                    counts: Some(WarcCounts::of(&summary)),
+                });
                 writer
                     .lock()
                     .expect("Could not get lock on writer!")
                     .write(url, summary)
                     .expect("Could not write URL summary!");
+                RunStats {
+                    succeeded: 1,
+                    ..Default::default()
+                }
             }
-            Err(e) => {
+            Some(Err(e @ ProcessError::Status(_))) => {
+                warn!("Skipping {}: {}", url, e);
+                on_done(&WarcProgress {
+                    warc: url,
+                    outcome: "skipped",
+                    reason: Some(e.to_string()),
+                    counts: None,
+                });
+                RunStats {
+                    skipped: 1,
+                    ..Default::default()
+                }
+            }
+            Some(Err(e)) => {
                 error!("Unknown error fetching {}: {}", url, e);
+                on_done(&WarcProgress {
+                    warc: url.clone(),
+                    outcome: "failed",
+                    reason: Some(e.to_string()),
+                    counts: None,
+                });
+                RunStats {
+                    failed: 1,
+                    failed_warcs: vec![FailedWarc {
+                        url,
+                        reason: e.to_string(),
+                        retryable: e.is_retryable(),
+                    }],
+                    ..Default::default()
+                }
             }
+        }
+    };
+
+    let stats = match options.max_concurrent_warcs {
+        // Plain threads rather than rayon's, so that a thread waiting for a
+        // WARC to start never holds up the records of one in progress.
+        Some(max) => {
+            let queue = Mutex::new(urls.into_iter());
+            let next_url = || queue.lock().expect("Could not get lock on queue!").next();
+            thread::scope(|scope| {
+                let workers: Vec<_> = (0..max.max(1))
+                    .map(|_| {
+                        scope.spawn(|| {
+                            let mut stats = RunStats::default();
+                            while let Some(url) = next_url() {
+                                stats = stats.merge(process(url));
+                            }
+                            stats
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().expect("WARC worker panicked"))
+                    .fold(RunStats::default(), RunStats::merge)
+            })
+        }
+        None => urls
+            .into_par_iter()
+            .map(process)
+            .reduce(RunStats::default, RunStats::merge),
+    };
+
+    RunStats {
+        aborted: aborted.load(Ordering::Relaxed),
+        ..stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+
+    fn warc_record(body: &str) -> Vec<u8> {
+        format!(
+            "WARC/1.0\r\nWARC-Type: response\r\nContent-Length: {}\r\n\r\n{}\r\n\r\n",
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+
+    fn gzip_member(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reads_every_gzip_member() {
+        let mut stream = gzip_member(&warc_record("first"));
+        stream.extend(gzip_member(&warc_record("second")));
+        let failed = Arc::new(AtomicBool::new(false));
+
+        let records: Vec<_> = read_records(Cursor::new(stream), failed.clone()).collect();
+
+        assert_eq!(records.len(), 2);
+        assert!(!failed.load(Ordering::Relaxed));
+    }
+
+    fn url_summary(url: &str, forms: &[&str]) -> URLSummary {
+        URLSummary {
+            url: url.to_owned(),
+            with_patterns: forms.iter().map(|form| form.to_string()).collect(),
+            language: None,
+            status: Some(200),
+            form_details: Vec::new(),
+            nr_patterns: 0,
+            nr_distinct_patterns: 0,
+        }
+    }
+
+    #[test]
+    fn summarize_adds_up_summaries() {
+        let with_forms = ArchiveSummary {
+            nr_unknown_encoding: 1,
+            nr_urls_without_patterns: 2,
+            nr_forms_without_patterns: 3,
+            urls_with_pattern_forms: vec![url_summary(
+                "https://example.com/",
+                &[r#"<form><input pattern="[0-9]+" ng-pattern="[a-z]+"></form>"#],
+            )],
+            ..Default::default()
+        };
+        let without_forms = ArchiveSummary {
+            nr_urls_without_patterns: 1,
+            nr_forms_without_patterns: 1,
+            ..Default::default()
+        };
+
+        let stats = summarize(vec![with_forms, without_forms].into_par_iter());
+
+        assert_eq!(stats.total_urls, 5);
+        assert_eq!(stats.successful_urls, 4);
+        assert_eq!(stats.urls_with_pattern, 1);
+        assert_eq!(stats.forms_with_pattern, 1);
+        assert_eq!(stats.total_forms, 5);
+        assert_eq!(stats.nr_unknown_encoding, 1);
+        assert_eq!(stats.nr_patterns, 2);
+        assert_eq!(stats.unknown_encoding_percent(), 20.0);
+    }
+
+    #[test]
+    fn stats_use_recorded_pattern_counts() {
+        let form = r#"<form><input pattern="[0-9]+"></form>"#;
+        let summary = ArchiveSummary {
+            urls_with_pattern_forms: vec![
+                URLSummary {
+                    nr_patterns: 3,
+                    ..url_summary("https://example.com/new", &[form])
+                },
+                url_summary("https://example.com/old", &[form]),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(Stats::from_summary(&summary).nr_patterns, 4);
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        let summary = ArchiveSummary {
+            nr_unknown_encoding: 3,
+            urls_with_pattern_forms: vec![url_summary(
+                "https://example.com/",
+                &[r#"<form><input pattern="[0-9]+"></form>"#],
+            )],
+            ..Default::default()
+        };
+        for codec in Codec::ALL {
+            let compressed = codec
+                .encode(Vec::new(), |w| {
+                    serde_json::to_writer(w, &summary).map_err(io::Error::from)
+                })
+                .unwrap();
+            assert_eq!(Codec::sniff(&compressed), Some(codec));
+
+            let file_name = env::temp_dir().join(format!(
+                "bo-cc-test-{}{}",
+                std::process::id(),
+                codec.extension()
+            ));
+            fs::write(&file_name, compressed).unwrap();
+            let read_back = ArchiveSummary::from_file(&file_name);
+            fs::remove_file(file_name).unwrap();
+
+            let read_back = read_back.unwrap();
+            assert_eq!(read_back.nr_unknown_encoding, 3);
+            assert_eq!(
+                read_back.urls_with_pattern_forms[0].with_patterns,
+                summary.urls_with_pattern_forms[0].with_patterns
+            );
+        }
+    }
+
+    #[test]
+    fn only_a_missing_index_is_empty() {
+        let dir =
+            FormsDir::new(env::temp_dir().join(format!("bo-cc-index-{}", std::process::id())));
+        fs::create_dir_all(dir.path()).unwrap();
+        assert!(dir.index_entries().unwrap().is_empty());
+
+        fs::write(dir.index_fn(), b"a.warc.gz\tok\nb\xff.warc.gz\tpartial\n").unwrap();
+        let entries = dir.index_entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].warc_url, "b\u{fffd}.warc.gz");
+        assert_eq!(entries[1].outcome, Outcome::Partial);
+
+        fs::remove_file(dir.index_fn()).unwrap();
+        fs::create_dir(dir.index_fn()).unwrap();
+        let unreadable = dir.index_entries();
+        fs::remove_dir_all(dir.path()).unwrap();
+        assert!(unreadable.is_err());
+    }
+
+    #[test]
+    fn charset_of_accepts_common_variants() {
+        for content_type in [
+            "text/html;charset=utf-8",
+            "text/html; charset=utf-8",
+            "text/html; charset=\"utf-8\"",
+            "text/html; Charset=UTF-8",
+            "text/html; charset='utf-8'",
+            "charset=utf-8; text/html",
+            "text/html; foo=bar; charset = utf-8 ",
+        ] {
+            assert_eq!(
+                charset_of(content_type).and_then(Encoding::for_label),
+                Some(encoding_rs::UTF_8),
+                "{content_type}"
+            );
+        }
+        assert_eq!(charset_of("text/html; charset='x'"), Some(b"x".as_slice()));
+        assert_eq!(charset_of("text/html"), None);
+        assert_eq!(charset_of("text/html; charset="), None);
+        assert_eq!(charset_of("text/html; charset=\"\""), None);
+    }
+
+    #[test]
+    fn encoding_comes_from_content_type_header() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html; Charset=\"ISO-8859-1\"\r\n\r\n<p>";
+        let payload = HttpPayload::parse(response).unwrap();
+        assert_eq!(
+            get_encoding_by_header(&payload),
+            Some(encoding_rs::WINDOWS_1252)
+        );
+
+        let payload = HttpPayload::parse(b"HTTP/1.1 200 OK\r\n\r\n<p>").unwrap();
+        assert_eq!(get_encoding_by_header(&payload), None);
+    }
+
+    #[test]
+    fn expands_only_non_void_self_closing_tags() {
+        assert_eq!(
+            expand_self_closing(
+                r#"<p><select name="a"/><input pattern="a/b>"/><!-- <x/> --><div/></p>"#
+            ),
+            r#"<p><select name="a"></select><input pattern="a/b>"/><!-- <x/> --><div></div></p>"#
+        );
+    }
+
+    #[test]
+    fn extracts_forms_from_xhtml() {
+        let response = concat!(
+            "HTTP/1.1 200 OK\r\n",
+            "Content-Type: application/xhtml+xml; charset=utf-8\r\n\r\n",
+            r#"<?xml version="1.0" encoding="utf-8"?>"#,
+            r#"<html xmlns="http://www.w3.org/1999/xhtml"><body>"#,
+            r#"<form id="a"><select name="country"/><textarea name="note"/></form>"#,
+            r#"<form id="b"><input name="zip" pattern="[0-9]{5}"/><br/></form>"#,
+            "</body></html>"
+        );
+
+        let forms = interesting_forms_in(response.as_bytes()).unwrap();
+
+        assert_eq!(forms.len(), 1);
+        assert!(forms[0].starts_with(r#"<form id="b">"#), "{}", forms[0]);
+        assert_eq!(
+            patterns_in(&forms[0]).unwrap(),
+            vec![(PatternSource::Html, "[0-9]{5}".to_owned())]
+        );
+    }
+
+    #[test]
+    fn storage_fn_round_trips() {
+        let dir = FormsDir::new("forms.d");
+        let sharded = "crawl-data/CC-MAIN-2023-40/segments/1695233505362.29/warc/\
+                       CC-MAIN-20230921073711-20230921103711-00000.warc.gz";
+        let legacy = "some/other/path.warc.gz";
+        for codec in Codec::ALL {
+            for warc_url in [sharded, legacy] {
+                for path in [
+                    dir.storage_fn(warc_url, codec),
+                    dir.legacy_storage_fn(warc_url, codec),
+                ] {
+                    assert_eq!(from_storage_fn(path).as_deref(), Some(warc_url));
+                }
+            }
+            assert_ne!(
+                dir.storage_fn(sharded, codec),
+                dir.legacy_storage_fn(sharded, codec)
+            );
+            assert_eq!(
+                dir.storage_fn(legacy, codec),
+                dir.legacy_storage_fn(legacy, codec)
+            );
+        }
+        assert_eq!(from_storage_fn("forms.d/not-a-summary.json"), None);
+    }
+
+    /// Read a request off `stream`, answer it with `body`, and return the
+    /// request's head.
+    fn answer(stream: TcpStream, status: &str, body: &[u8]) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        while reader.read_line(&mut head).unwrap() > 0 && !head.ends_with("\r\n\r\n") {}
+        let stream = reader.get_mut();
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        head
+    }
+
+    #[test]
+    fn parses_commoncrawl_warc_paths() {
+        assert_eq!(
+            parse_warc_path(
+                "crawl-data/CC-MAIN-2023-40/segments/1695233505362.29/warc/\
+                 CC-MAIN-20230921073711-20230921103711-00000.warc.gz"
+            ),
+            Some(WarcPathInfo {
+                crawl: "CC-MAIN-2023-40".to_owned(),
+                segment: "1695233505362.29".to_owned(),
+                filename: "CC-MAIN-20230921073711-20230921103711-00000.warc.gz".to_owned(),
+            })
+        );
+
+        for malformed in [
+            "crawl-info/CC-MAIN-2023-40/segments/1695233505362.29/warc/x.warc.gz",
+            "crawl-data/CC-MAIN-2023-40/segment/1695233505362.29/warc/x.warc.gz",
+            "crawl-data/CC-MAIN-2023-40/segments/1695233505362.29/warc",
+            "crawl-data/CC-MAIN-2023-40/segments/1695233505362.29/warc/x.warc.gz/y",
+            "crawl-data//segments/1695233505362.29/warc/x.warc.gz",
+            "crawl-data/CC-MAIN-2023-40/segments//warc/x.warc.gz",
+            "crawl-data/CC-MAIN-2023-40/segments/1695233505362.29/warc/",
+            "x.warc.gz",
+            "",
+        ] {
+            assert_eq!(parse_warc_path(malformed), None, "{malformed}");
+        }
+    }
+
+    #[test]
+    fn clones_share_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        let mut client = Client::with_options(ClientOptions {
+            base_url: Some(base_url.clone()),
+            ..Default::default()
+        });
+        let clone = client.clone();
+        let server_url = base_url.clone();
+        let server = thread::spawn(move || {
+            let mut waits = Vec::new();
+            for status in ["500 Internal Server Error", "200 OK"] {
+                let (stream, _) = listener.accept().unwrap();
+                waits.push(clone.current_wait(&clone.limit_for(&server_url)));
+                answer(stream, status, b"");
+            }
+            waits
         });
+
+        let response = client.get("some.warc.gz").unwrap();
+
+        assert!(response.status().is_success());
+        // The clone saw the backoff after the server error, before the retry.
+        assert_eq!(
+            server.join().unwrap(),
+            vec![Duration::ZERO, Duration::from_secs(1)]
+        );
+        assert_eq!(
+            client.current_wait(&client.limit_for(&base_url)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn summary_totals_and_emptiness() {
+        assert!(ArchiveSummary::default().is_empty());
+        for summary in [
+            ArchiveSummary {
+                nr_records_seen: 1,
+                ..Default::default()
+            },
+            ArchiveSummary {
+                nr_lossy_decode: 1,
+                ..Default::default()
+            },
+            ArchiveSummary {
+                encodings: HashMap::from([("UTF-8".to_owned(), 1)]),
+                ..Default::default()
+            },
+        ] {
+            assert!(!summary.is_empty());
+        }
+
+        let summary = ArchiveSummary {
+            nr_unknown_encoding: 1,
+            nr_urls_without_patterns: 2,
+            nr_forms_without_patterns: 4,
+            nr_collapsed_forms: 1,
+            urls_with_pattern_forms: vec![
+                url_summary("https://a.example/", &["<form></form>", "<form></form>"]),
+                url_summary("https://b.example/", &["<form></form>"]),
+            ],
+            ..Default::default()
+        };
+        assert!(!summary.is_empty());
+        assert_eq!(summary.successful_urls(), 4);
+        assert_eq!(summary.total_urls(), 5);
+        assert_eq!(summary.forms_with_patterns(), 4);
+        assert_eq!(summary.total_forms(), 8);
+    }
+
+    #[test]
+    fn finds_patterns_in_bare_form_fragments() {
+        let fragment = r#"<form action="/s"><fieldset><input name="q" pattern="[a-z]+" required>
+            <input data-val-regex-pattern="\d{3}"></fieldset><input name="x"></form>"#;
+        assert_eq!(
+            patterns_in(fragment).unwrap(),
+            vec![
+                (PatternSource::Html, "[a-z]+".to_owned()),
+                (PatternSource::AspNet, "\\d{3}".to_owned()),
+            ]
+        );
+        assert_eq!(
+            patterns_in(r#"<form><input pattern="x"></form>"#).unwrap(),
+            vec![(PatternSource::Html, "x".to_owned())]
+        );
+        assert!(patterns_in("<form></form>").unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_every_pattern_on_one_input() {
+        let form = r#"<form><input ng-pattern="c" data-val-regex-pattern="b" pattern="a"></form>"#;
+        assert_eq!(
+            patterns_in(form).unwrap(),
+            vec![
+                (PatternSource::Html, "a".to_owned()),
+                (PatternSource::AspNet, "b".to_owned()),
+                (PatternSource::Angular, "c".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn looks_up_urls_with_fragments() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = Client::with_options(ClientOptions {
+            index_url: Some(format!("http://{}/", listener.local_addr().unwrap())),
+            unlimited: true,
+            ..Default::default()
+        });
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let cdx_line = r#"{"url": "https://example.com/page#top", "filename": "crawl-data/x.warc.gz", "offset": "10", "length": "20", "status": "200"}"#;
+            answer(stream, "200 OK", cdx_line.as_bytes())
+        });
+
+        let entries = client
+            .lookup("CC-MAIN-2023-40", "https://example.com/page#top")
+            .unwrap();
+
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with(
+                "GET /CC-MAIN-2023-40-index?url=https%3A%2F%2Fexample.com%2Fpage%23top&output=json "
+            ),
+            "{request}"
+        );
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].url, "https://example.com/page#top");
+        assert_eq!(entries[0].filename, "crawl-data/x.warc.gz");
+        assert_eq!(
+            (entries[0].offset.as_str(), entries[0].length.as_str()),
+            ("10", "20")
+        );
+    }
+
+    #[test]
+    fn reads_patterns_from_configured_attributes() {
+        let form = r#"<form><input data-pattern="[0-9]+"><input pattern="x"></form>"#;
+        assert_eq!(
+            patterns_in_with(form, &["data-pattern", "pattern"]).unwrap(),
+            vec![
+                (PatternSource::Custom, "[0-9]+".to_owned()),
+                (PatternSource::Html, "x".to_owned()),
+            ]
+        );
+
+        let summary = ArchiveSummary {
+            urls_with_pattern_forms: vec![url_summary("https://example.com/", &[form])],
+            pattern_attrs: vec!["data-pattern".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(Stats::from_summary(&summary).nr_patterns, 1);
+        let summary = ArchiveSummary {
+            pattern_attrs: Vec::new(),
+            ..summary
+        };
+        assert_eq!(Stats::from_summary(&summary).nr_patterns, 1);
+        assert_eq!(summary.pattern_attrs_or_default(), default_pattern_attrs());
+    }
+
+    #[test]
+    fn get_record_needs_a_partial_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = Client::with_options(ClientOptions {
+            base_url: Some(format!("http://{}/", listener.local_addr().unwrap())),
+            unlimited: true,
+            ..Default::default()
+        });
+        let server = thread::spawn(move || {
+            let record = gzip_member(&warc_record("HTTP/1.1 200 OK\r\n\r\n<p>"));
+            ["206 Partial Content", "200 OK"]
+                .into_iter()
+                .map(|status| answer(listener.accept().unwrap().0, status, &record))
+                .collect::<Vec<_>>()
+        });
+        let entry = CdxEntry {
+            url: "https://example.com/".to_owned(),
+            filename: "crawl-data/x.warc.gz".to_owned(),
+            offset: "10".to_owned(),
+            length: "20".to_owned(),
+        };
+
+        let record = client.get_record(&entry).unwrap();
+        assert_eq!(record.content, b"HTTP/1.1 200 OK\r\n\r\n<p>");
+        assert!(client.get_record(&entry).is_err());
+
+        for request in server.join().unwrap() {
+            assert!(
+                request.starts_with("GET /crawl-data/x.warc.gz "),
+                "{request}"
+            );
+            assert!(
+                request
+                    .to_ascii_lowercase()
+                    .contains("range: bytes=10-29\r\n"),
+                "{request}"
+            );
+        }
+    }
+
+    #[test]
+    fn elements_with_finds_inputs_by_pattern() {
+        let form = r#"<form><input name="a" ng-pattern="[0-9]+"><input pattern="x"></form>"#;
+        assert_eq!(
+            elements_with(form, "[0-9]+").unwrap(),
+            vec![ElementMatch {
+                html: r#"<input name="a" ng-pattern="[0-9]+">"#.to_owned(),
+                source: Some(PatternSource::Angular),
+                pattern: Some("[0-9]+".to_owned()),
+            }]
+        );
+        assert!(elements_with(form, "y").unwrap().is_empty());
+    }
+
+    fn response_record(http_response: &str) -> WarcRecord {
+        let warc = format!(
+            "WARC/1.0\r\nWARC-Type: response\r\nWARC-Target-URI: https://example.com/\r\n\
+             Content-Length: {}\r\n\r\n{}\r\n\r\n",
+            http_response.len(),
+            http_response
+        );
+        let failed = Arc::new(AtomicBool::new(false));
+        read_records(Cursor::new(gzip_member(warc.as_bytes())), failed)
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn only_html_counts_as_non_success() {
+        let options = ProcessingOptions {
+            only_success: true,
+            ..Default::default()
+        };
+
+        let html = response_record("HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\n\r\n<p>");
+        let summary = ArchiveSummary::from_record(html, &options).unwrap();
+        assert_eq!(summary.nr_non_success, 1);
+        assert_eq!(summary.nr_skipped_non_html, 0);
+
+        let image = response_record("HTTP/1.1 404 Not Found\r\nContent-Type: image/png\r\n\r\nPNG");
+        let summary = ArchiveSummary::from_record(image, &options).unwrap();
+        assert_eq!(summary.nr_non_success, 0);
+        assert_eq!(summary.nr_skipped_non_html, 1);
+        assert_eq!(summary.nr_html_records(), 0);
+    }
+
+    #[test]
+    fn sample_keys_spread_across_pages() {
+        let mut keys: Vec<(u64, usize)> = (0..100)
+            .flat_map(|page| {
+                let url = format!("https://example.com/{page}");
+                (0..10).map(move |i| (sample_key(7, &url, i), page))
+            })
+            .collect();
+        keys.sort_unstable();
+        let pages: HashSet<usize> = keys[..100].iter().map(|&(_, page)| page).collect();
+        // A uniform sample of 100 of the 1000 forms hits about 65 pages.
+        assert!(pages.len() > 40, "sample hit only {} pages", pages.len());
+    }
 }